@@ -1,33 +1,126 @@
 use std::env;
 use std::fs;
-use std::io::{self, Write, Read};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+#[cfg(unix)]
 use std::os::unix::fs::{MetadataExt, PermissionsExt};
 use std::path::{Path, PathBuf};
-use std::process;
+use std::process::{self, Command, Stdio};
 use std::num::NonZeroU64;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use zopfli::{GzipEncoder, Options, BlockType};
-use flate2::read::GzDecoder;
+use flate2::read::{GzDecoder, MultiGzDecoder};
 
 const MAGIC: &[u8] = b"# compressed by zexe";
 const HEADER_SIZE: usize = 512;
+const VERSION: &str = env!("CARGO_PKG_VERSION");
 const AUTHOR: &str = "Philippe TEMESI";
 const YEAR: &str = "2026";
 const WEBSITE: &str = "https://www.tems.be";
+const TEMP_PREFIX: &str = "zexe.";
+const STALE_TEMP_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+const BUSY_TIMEOUT: Duration = Duration::from_secs(60);
+const BUSY_RETRY_INTERVAL: Duration = Duration::from_millis(250);
+const CIPHER: &str = "aes-256-cbc";
+const PBKDF2_ITERATIONS: u32 = 100_000;
+const PASS_ENV: &str = "ZEXE_PASS";
+const PASS_FILE_ENV: &str = "ZEXE_PASS_FILE";
+const LEVEL_ENV: &str = "ZEXE_LEVEL";
+const EXIT_ERROR: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_REFUSED: i32 = 3;
+const EXIT_IO: i32 = 4;
+const EXIT_DAMAGED: i32 = 5;
+const ENCRYPTED_MARKER: &[u8] = b"# encrypted with openssl";
+const CACHED_MARKER: &[u8] = b"# extracted once into the user cache";
+const EXTRACT_ONLY_MARKER: &[u8] = b"# extracts without running";
+const STORED_MARKER: &[u8] = b"# stored without compression";
+const CKSUM_PREFIX: &[u8] = b"# original cksum: ";
+const NAME_PREFIX: &[u8] = b"# original name: ";
+const MODE_PREFIX: &[u8] = b"# original mode: ";
+const XATTR_PREFIX: &[u8] = b"# xattr: ";
+const SIGNER_PREFIX: &[u8] = b"# signed by: ";
+const SIGNATURE_PREFIX: &[u8] = b"# signature: ";
+const BUNDLE_PREFIX: &[u8] = b"# bundle with entry point: ";
+const ARGS_PREFIX: &[u8] = b"# default arguments: ";
+
+// Picks the first gzip the target has into $z
+const FIND_GZIP: &str = r#"for z in gzip pigz "busybox gzip"; do
+    command -v ${z%% *} >/dev/null 2>&1 && break; z=
+done
+[ -n "$z" ] || { echo "$0: error: 'gzip' not found; install gzip (pigz or busybox gzip also work)" >&2; exit 1; }
+"#;
+
+// What the command line asks for: help and version are not errors and
+// exit with status 0
+#[derive(Debug)]
+enum Action {
+    Run(Box<Config>),
+    Help,
+    // With -v, the build details follow the version line
+    Version { verbose: bool },
+    Completions(String),
+    SelfTest,
+}
 
 #[derive(Debug)]
 struct Config {
     decompress: bool,
+    benchmark: bool,
+    gc: bool,
+    info: bool,
+    test: bool,
+    dry_run: bool,
+    json: bool,
     files: Vec<PathBuf>,
     compression_level: CompressionLevel,
+    level_map: Vec<(String, CompressionLevel)>,
+    default_args: Vec<String>,
+    auto: bool,
+    store: bool,
     iterations: Option<NonZeroU64>,
     iterations_without_improvement: Option<NonZeroU64>,
     max_block_splits: Option<u16>,
     block_type: BlockType,
+    threads: usize,
+    encrypt: bool,
+    cache: bool,
+    extract_only: bool,
+    data: bool,
+    no_xattr: bool,
+    no_sparse: bool,
+    max_output: Option<u64>,
+    cksum_check: bool,
+    recursive: bool,
+    output_dir: Option<PathBuf>,
+    bundle: Option<PathBuf>,
+    entry: Option<String>,
+    include: Vec<String>,
+    exclude: Vec<String>,
+    force: bool,
+    fail_fast: bool,
+    backup_suffix: Option<String>,
+    wait: bool,
+    allow_setuid: bool,
+    preserve_caps: bool,
+    any: bool,
+    dereference: bool,
+    min_ratio: Option<f64>,
+    strip: bool,
+    sign_key: Option<PathBuf>,
+    gpg_key: Option<String>,
+    verify_sig: bool,
+    compare: bool,
+    compare_with: Option<PathBuf>,
+    pubkey: Option<PathBuf>,
+    password_file: Option<PathBuf>,
     verbose: bool,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 enum CompressionLevel {
     Fast,      // Compression rapide, moins bonne
     Normal,    // Équilibre (défaut)
@@ -36,7 +129,24 @@ enum CompressionLevel {
     Custom,    // Paramètres personnalisés
 }
 
+const PRESET_LEVELS: [CompressionLevel; 4] = [
+    CompressionLevel::Fast,
+    CompressionLevel::Normal,
+    CompressionLevel::Maximum,
+    CompressionLevel::Ultra,
+];
+
 impl CompressionLevel {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "1" | "fast" => Some(CompressionLevel::Fast),
+            "2" | "normal" | "balanced" => Some(CompressionLevel::Normal),
+            "3" | "maximum" | "max" => Some(CompressionLevel::Maximum),
+            "4" | "ultra" => Some(CompressionLevel::Ultra),
+            _ => None,
+        }
+    }
+
     fn as_str(&self) -> &'static str {
         match self {
             CompressionLevel::Fast => "fast",
@@ -48,6 +158,96 @@ impl CompressionLevel {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BinaryFormat {
+    Elf,
+    MachO,     // 32/64 bits, les deux boutismes
+    MachOFat,  // Universal binary (même magic que les .class Java)
+    Pe,        // Windows, pour Wine via binfmt_misc
+}
+
+impl BinaryFormat {
+    fn as_str(&self) -> &'static str {
+        match self {
+            BinaryFormat::Elf => "ELF",
+            BinaryFormat::MachO => "Mach-O",
+            BinaryFormat::MachOFat => "Mach-O universal",
+            BinaryFormat::Pe => "PE",
+        }
+    }
+}
+
+// Reasons a file is refused. They travel inside io::Error like any other
+// failure; ZexeError::of gets them back, e.g. to choose the exit status.
+#[derive(Debug, Clone, PartialEq)]
+enum ZexeError {
+    AlreadyCompressed(String),
+    NotCompressed,
+    NotFound,
+    NotRegularFile,
+    IsDirectory,
+    NotExecutable,
+    Setuid,
+    Capabilities,
+    NotBinary,
+    Symlink,
+    BackupExists(PathBuf),
+    OutputExists(PathBuf),
+    HardLinked(u64),
+    Empty,
+}
+
+impl ZexeError {
+    fn of(error: &io::Error) -> Option<&ZexeError> {
+        error.get_ref()?.downcast_ref()
+    }
+
+    fn kind(&self) -> io::ErrorKind {
+        match self {
+            ZexeError::AlreadyCompressed(_) | ZexeError::BackupExists(_) | ZexeError::OutputExists(_) => {
+                io::ErrorKind::AlreadyExists
+            }
+            ZexeError::NotFound => io::ErrorKind::NotFound,
+            _ => io::ErrorKind::InvalidInput,
+        }
+    }
+}
+
+impl std::fmt::Display for ZexeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ZexeError::AlreadyCompressed(packing) => write!(f, "already packed by zexe ({}); use -d to unpack it \
+                                                                 or --force to pack it again", packing),
+            ZexeError::NotCompressed => write!(f, "file not compressed"),
+            ZexeError::NotFound => write!(f, "file does not exist"),
+            ZexeError::NotRegularFile => write!(f, "not a regular file"),
+            ZexeError::IsDirectory => write!(f, "is a directory (use --recursive to process the files inside)"),
+            ZexeError::NotExecutable => write!(f, "not executable"),
+            ZexeError::Setuid => write!(f, "has setuid/setgid bits set (use --allow-setuid to pack it anyway)"),
+            ZexeError::Capabilities => write!(f, "has Linux file capabilities, which the extracted copy would run \
+                                                 without (use --force or --preserve-caps to pack it anyway)"),
+            ZexeError::NotBinary => write!(f, "not an ELF, Mach-O or PE binary (use --any to pack scripts and other files)"),
+            ZexeError::Symlink => write!(f, "is a symbolic link (not followed, it would be replaced by a regular file); \
+                                             use --dereference to process its target in place"),
+            ZexeError::BackupExists(backup) => write!(f, "backup {} already exists (use --force to replace it)",
+                                                      backup.display()),
+            ZexeError::OutputExists(output) => write!(f, "{} already exists (use --force to replace it)",
+                                                      output.display()),
+            ZexeError::Empty => write!(f, "is empty, there is nothing to pack"),
+            ZexeError::HardLinked(links) => write!(f, "has {} other hard link(s) that would keep the uncompressed \
+                                                       contents (use --force to pack this name anyway)", links),
+        }
+    }
+}
+
+impl std::error::Error for ZexeError {}
+
+impl From<ZexeError> for io::Error {
+    fn from(error: ZexeError) -> Self {
+        io::Error::new(error.kind(), error)
+    }
+}
+
 #[derive(Debug)]
 struct FileInfo {
     path: PathBuf,
@@ -60,7 +260,7 @@ impl FileInfo {
         if self.original_size == 0 {
             0.0
         } else {
-            (self.original_size - self.compressed_size) as f64 * 100.0 / self.original_size as f64
+            (self.original_size as f64 - self.compressed_size as f64) * 100.0 / self.original_size as f64
         }
     }
 }
@@ -68,66 +268,409 @@ impl FileInfo {
 fn main() {
     if let Err(e) = run() {
         eprintln!("Error: {}", e);
-        process::exit(1);
+        process::exit(exit_status(&e));
     }
 }
 
 fn run() -> io::Result<()> {
-    let config = parse_args()?;
-    let mut exit_code = 0;
+    let args: Vec<String> = env::args().collect();
+    let action = parse_args(&args).unwrap_or_else(|e| {
+        eprintln!("Error: {}", e);
+        process::exit(EXIT_USAGE);
+    });
+    let config = match action {
+        Action::Run(config) => *config,
+        Action::Help => {
+            print_help(&args[0]);
+            return Ok(());
+        }
+        Action::Version { verbose } => {
+            print_version(verbose);
+            return Ok(());
+        }
+        Action::Completions(shell) => {
+            print_completions(&shell);
+            return Ok(());
+        }
+        Action::SelfTest => return self_test(&args[0]),
+    };
+
+    if config.gc {
+        let removed = gc_temp_dirs(&env::temp_dir(), STALE_TEMP_AGE, config.verbose)?;
+        report(&config, format_args!("Removed {} stale temporary director{}", removed,
+                                     if removed == 1 { "y" } else { "ies" }));
+        if config.files.is_empty() {
+            process::exit(0);
+        }
+    }
+    if let Some(out) = &config.bundle {
+        let files: Vec<PathBuf> = expand_files(&config)?.into_iter().map(|input| input.path).collect();
+        let info = bundle_files(out, &files, &config)?;
+        println!("{}: {} files, {} -> {} bytes, {:.1}% compression (Zopfli - {}){}",
+                 info.path.display(), files.len(), info.original_size, info.compressed_size,
+                 info.compression_ratio(), config.compression_level.as_str(),
+                 if config.dry_run { " [dry run]" } else { "" });
+        return Ok(());
+    }
+
+    let mut totals = FileInfo {
+        path: PathBuf::new(),
+        original_size: 0,
+        compressed_size: 0,
+    };
+    let mut processed = 0;
+    let mut skipped = 0;
+    let mut attempted = 0;
+    let mut failures = Vec::new();
+    let files = expand_files(&config)?;
+    if let Some(dir) = &config.output_dir {
+        check_output_paths(&files, dir).unwrap_or_else(|e| {
+            eprintln!("Error: {}", e);
+            process::exit(EXIT_USAGE);
+        });
+    }
+
+    // -d with --threads unpacks several files at once; the results are
+    // still reported below in the order of the files
+    let mut unpacked = if config.decompress && config.threads > 1 && files.len() > 1 {
+        unpack_files(&files, &config)
+    } else {
+        Vec::new()
+    };
 
     // CORRECTION: Itérer sur une référence avec &config.files
-    for file in &config.files {
-        let result = if config.decompress {
-            decompress_file(file)  // Note: on passe &file directement
-        } else {
-            compress_file(file, &config)  // Note: on passe &file directement
+    for (index, Input { path: file, relative }) in files.iter().enumerate() {
+        let (result, elapsed) = match unpacked.get_mut(index) {
+            Some(done) => match done.take() {
+                Some(done) => done,
+                // Not started after a failure with --fail-fast
+                None => continue,
+            },
+            None => {
+                let start = Instant::now();
+                (process_file(file, relative, &config), start.elapsed())
+            }
         };
+        attempted += 1;
 
+        if config.json {
+            print_json_result(file, relative, &result, elapsed, &config);
+        } else if let Ok(Some(info)) = &result {
+            if config.decompress {
+                println!("{}: decompressed ({} -> {} bytes, {:.1}% saved){}",
+                         info.path.display(), info.compressed_size, info.original_size,
+                         info.compression_ratio(),
+                         if config.dry_run { " [dry run]" } else { "" });
+            } else {
+                let method = if config.store {
+                    "stored".to_string()
+                } else {
+                    format!("Zopfli - {}", if config.auto { "auto" } else { file_level(file, &config).as_str() })
+                };
+                println!("{}: {} -> {} bytes, {:.1}% compression ({}){}",
+                         info.path.display(), info.original_size, info.compressed_size,
+                         info.compression_ratio(), method,
+                         if config.dry_run { " [dry run]" } else { "" });
+            }
+        }
         match result {
             Ok(Some(info)) => {
-                if config.decompress {
-                    println!("{}: decompressed ({} -> {} bytes, {:.1}% saved)",
-                             info.path.display(), info.compressed_size, info.original_size,
-                             info.compression_ratio());
-                } else {
-                    println!("{}: {} -> {} bytes, {:.1}% compression (Zopfli - {})",
-                             info.path.display(), info.original_size, info.compressed_size,
-                             info.compression_ratio(), config.compression_level.as_str());
-                }
+                totals.original_size += info.original_size;
+                totals.compressed_size += info.compressed_size;
+                processed += 1;
             }
-            Ok(None) => {}
+            Ok(None) => skipped += 1,
             Err(e) => {
                 eprintln!("{}: {}", file.display(), e);
-                exit_code = 1;
+                failures.push((file, e));
+                // Files unpacked in parallel are done already: report them
+                if config.fail_fast && unpacked.is_empty() {
+                    break;
+                }
             }
         }
     }
 
-    process::exit(exit_code);
+    if config.json {
+        println!("{{\"summary\":true,\"processed\":{},\"skipped\":{},\"failed\":{},\"untouched\":{},\
+                  \"original_size\":{},\"compressed_size\":{},\"ratio\":{:.1}}}",
+                 processed, skipped, failures.len(), files.len() - attempted,
+                 totals.original_size, totals.compressed_size, totals.compression_ratio());
+    } else if processed > 1 {
+        if config.decompress {
+            println!("Unpacked {} files: {} -> {} ({:.1}% saved)",
+                     processed, format_size(totals.compressed_size),
+                     format_size(totals.original_size), totals.compression_ratio());
+        } else {
+            println!("Packed {} files: {} -> {} ({:.1}% saved)",
+                     processed, format_size(totals.original_size),
+                     format_size(totals.compressed_size), totals.compression_ratio());
+        }
+    }
+
+    if files.len() > 1 && !failures.is_empty() {
+        eprintln!("Processed {} files: {} succeeded, {} failed",
+                  attempted, attempted - failures.len(), failures.len());
+        if attempted < files.len() {
+            eprintln!("Stopped at the first failure (--fail-fast), {} files left untouched",
+                      files.len() - attempted);
+        }
+        for (file, e) in &failures {
+            eprintln!("  {}: {}", file.display(), e);
+        }
+    }
+
+    // A specific status only when it covers every failure
+    let status = match failures.split_first() {
+        None => 0,
+        Some(((_, first), rest)) => {
+            let status = exit_status(first);
+            if rest.iter().all(|(_, e)| exit_status(e) == status) { status } else { EXIT_ERROR }
+        }
+    };
+    process::exit(status);
 }
 
-fn parse_args() -> io::Result<Config> {
-    let args: Vec<String> = env::args().collect();
+fn exit_status(error: &io::Error) -> i32 {
+    if ZexeError::of(error).is_some() {
+        EXIT_REFUSED
+    } else if error.kind() == io::ErrorKind::InvalidData {
+        EXIT_DAMAGED
+    } else {
+        EXIT_IO
+    }
+}
+
+fn process_file(file: &Path, relative: &Path, config: &Config) -> io::Result<Option<FileInfo>> {
+    // Before anything opens the file, so that every action reports it the same way
+    if let Err(e) = fs::symlink_metadata(file) {
+        return Err(if e.kind() == io::ErrorKind::NotFound { ZexeError::NotFound.into() } else { e });
+    }
+    if file.is_dir() {
+        Err(ZexeError::IsDirectory.into())
+    } else if config.verify_sig {
+        verify_file(file, config)
+    } else if config.compare {
+        compare_file(file, config)
+    } else if config.info {
+        info_file(file)
+    } else if config.test {
+        test_integrity(file, config)
+    } else if config.benchmark {
+        benchmark_file(file, config)
+    } else if config.decompress {
+        decompress_file(file, config)
+    } else {
+        match &config.output_dir {
+            Some(dir) => compress_file_to(file, Some(&dir.join(relative)), config),
+            None => compress_file(file, config),
+        }
+    }
+}
+
+type Outcome = (io::Result<Option<FileInfo>>, Duration);
+
+// Unpacks `files` on up to --threads threads, each taking the next file
+// not started yet. With --fail-fast no file is started after a failure and
+// those files are left as None.
+fn unpack_files(files: &[Input], config: &Config) -> Vec<Option<Outcome>> {
+    let next = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    let outcomes: Vec<Mutex<Option<Outcome>>> = files.iter().map(|_| Mutex::new(None)).collect();
+    thread::scope(|scope| {
+        for _ in 0..config.threads.min(files.len()) {
+            scope.spawn(|| loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                if index >= files.len() || (config.fail_fast && failed.load(Ordering::Relaxed)) {
+                    break;
+                }
+                let Input { path, relative } = &files[index];
+                let start = Instant::now();
+                let result = process_file(path, relative, config);
+                failed.fetch_or(result.is_err(), Ordering::Relaxed);
+                *outcomes[index].lock().unwrap() = Some((result, start.elapsed()));
+            });
+        }
+    });
+    outcomes.into_iter().map(|outcome| outcome.into_inner().unwrap()).collect()
+}
+
+// Progress and notices: stdout normally, stderr with --json so that stdout
+// only carries JSON
+fn report(config: &Config, message: std::fmt::Arguments) {
+    if config.json {
+        eprintln!("{}", message);
+    } else {
+        println!("{}", message);
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+// One line per file for --json; `skipped` covers --min-ratio
+fn print_json_result(file: &Path, relative: &Path, result: &io::Result<Option<FileInfo>>,
+                     elapsed: Duration, config: &Config) {
+    let action = if config.decompress { "unpack" } else { "pack" };
+    let mut fields = vec![
+        format!("\"file\":{}", json_string(&file.to_string_lossy())),
+        format!("\"action\":\"{}\"", action),
+    ];
+    match result {
+        Ok(Some(info)) => {
+            let output = match &config.output_dir {
+                Some(dir) => dir.join(relative),
+                None => info.path.clone(),
+            };
+            fields.push("\"status\":\"ok\"".to_string());
+            fields.push(format!("\"output\":{}", json_string(&output.to_string_lossy())));
+            fields.push(format!("\"original_size\":{}", info.original_size));
+            fields.push(format!("\"compressed_size\":{}", info.compressed_size));
+            fields.push(format!("\"ratio\":{:.1}", info.compression_ratio()));
+            if !config.decompress {
+                let compression = if config.store {
+                    Some("stored")
+                } else if config.dry_run {
+                    None
+                } else {
+                    // The packed file tells whether --auto ended up storing
+                    fs::File::open(&output).and_then(|mut f| read_header(&mut f)).ok()
+                        .map(|header| if header_has(&header, STORED_MARKER) { "stored" } else { "zopfli" })
+                };
+                let level = (!config.store)
+                    .then(|| if config.auto { "auto" } else { file_level(file, config).as_str() });
+                fields.push(format!("\"compression\":{}", compression.map_or("null".to_string(), json_string)));
+                fields.push(format!("\"level\":{}", level.map_or("null".to_string(), json_string)));
+            }
+        }
+        Ok(None) => fields.push("\"status\":\"skipped\"".to_string()),
+        Err(e) => {
+            fields.push("\"status\":\"error\"".to_string());
+            fields.push(format!("\"error\":{}", json_string(&e.to_string())));
+        }
+    }
+    fields.push(format!("\"dry_run\":{}", config.dry_run));
+    fields.push(format!("\"duration\":{:.3}", elapsed.as_secs_f64()));
+    println!("{{{}}}", fields.join(","));
+}
+
+fn parse_args(args: &[String]) -> io::Result<Action> {
     let mut decompress = false;
+    let mut benchmark = false;
+    let mut gc = false;
+    let mut info = false;
+    let mut test = false;
+    let mut dry_run = false;
+    let mut json = false;
     let mut files = Vec::new();
-    let mut compression_level = CompressionLevel::Normal;
+    let mut level = None;
+    let mut level_map = Vec::new();
+    let mut default_args = Vec::new();
+    let mut auto = false;
+    let mut store = false;
     let mut iterations = None;
     let mut iterations_without_improvement = None;
     let mut max_block_splits = None;
     let mut block_type = BlockType::Dynamic;
+    let mut threads = 1;
+    let mut encrypt = false;
+    let mut cache = false;
+    let mut extract_only = false;
+    let mut data = false;
+    let mut no_xattr = false;
+    let mut no_sparse = false;
+    let mut max_output: Option<u64> = None;
+    let mut cksum_check = false;
+    let mut recursive = false;
+    let mut output_dir: Option<PathBuf> = None;
+    let mut bundle: Option<PathBuf> = None;
+    let mut entry: Option<String> = None;
+    let mut include = Vec::new();
+    let mut exclude = Vec::new();
+    let mut force = false;
+    let mut fail_fast = false;
+    let mut backup_suffix: Option<String> = None;
+    let mut wait = false;
+    let mut allow_setuid = false;
+    let mut preserve_caps = false;
+    let mut any = false;
+    let mut dereference = false;
+    let mut min_ratio = None;
+    let mut strip = false;
+    let mut sign_key = None;
+    let mut gpg_key = None;
+    let mut verify_sig = false;
+    let mut compare = false;
+    let mut compare_with: Option<PathBuf> = None;
+    let mut pubkey = None;
+    let mut password_file = None;
     let mut verbose = false;
 
     let mut i = 1;
     while i < args.len() {
         match args[i].as_str() {
             "-d" => decompress = true,
-            "-1" | "--fast" => compression_level = CompressionLevel::Fast,
-            "-2" | "--normal" => compression_level = CompressionLevel::Normal,
-            "-3" | "--maximum" => compression_level = CompressionLevel::Maximum,
-            "-4" | "--ultra" => compression_level = CompressionLevel::Ultra,
+            "--benchmark" => benchmark = true,
+            "--gc" => gc = true,
+            "-l" | "--info" => info = true,
+            "-t" | "--test" => test = true,
+            "-n" | "--dry-run" => dry_run = true,
+            "--json" => json = true,
+            "-1" | "--fast" => choose_level(&mut level, &args[i], CompressionLevel::Fast)?,
+            "-2" | "--normal" | "--balanced" => choose_level(&mut level, &args[i], CompressionLevel::Normal)?,
+            "-3" | "--maximum" | "--max" => choose_level(&mut level, &args[i], CompressionLevel::Maximum)?,
+            "-4" | "--ultra" => choose_level(&mut level, &args[i], CompressionLevel::Ultra)?,
+            "--compress-level" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                        "Missing value for --compress-level"));
+                }
+                let named = CompressionLevel::from_name(&args[i])
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput,
+                        format!("Unknown compression level: {}", args[i])))?;
+                choose_level(&mut level, &format!("--compress-level {}", args[i]), named)?;
+            }
+            "--auto" => auto = true,
+            "--map" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                        "Missing value for --map"));
+                }
+                let (ext, name) = args[i].split_once('=')
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput,
+                        "--map takes EXT=LEVEL, e.g. so=ultra"))?;
+                let mapped = CompressionLevel::from_name(name)
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput,
+                        format!("Unknown compression level: {}", name)))?;
+                level_map.push((ext.trim_start_matches('.').to_string(), mapped));
+            }
+            "--args" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                        "Missing value for --args"));
+                }
+                default_args.extend(split_args(&args[i]).ok_or_else(|| io::Error::new(
+                    io::ErrorKind::InvalidInput, format!("Unterminated quote in --args: {}", args[i])))?);
+            }
+            "-0" | "--store" => store = true,
             "--custom" => {
-                compression_level = CompressionLevel::Custom;
+                choose_level(&mut level, &args[i], CompressionLevel::Custom)?;
                 // Les paramètres personnalisés seront lus via d'autres options
             }
             "--iterations" => {
@@ -144,7 +687,7 @@ fn parse_args() -> io::Result<Config> {
                         "Iterations must be > 0"));
                 }
                 iterations = Some(NonZeroU64::new(val).unwrap());
-                compression_level = CompressionLevel::Custom;
+                choose_level(&mut level, &args[i - 1], CompressionLevel::Custom)?;
             }
             "--iter-without-improvement" => {
                 i += 1;
@@ -160,7 +703,7 @@ fn parse_args() -> io::Result<Config> {
                         "Iterations without improvement must be > 0"));
                 }
                 iterations_without_improvement = Some(NonZeroU64::new(val).unwrap());
-                compression_level = CompressionLevel::Custom;
+                choose_level(&mut level, &args[i - 1], CompressionLevel::Custom)?;
             }
             "--max-block-splits" => {
                 i += 1;
@@ -172,7 +715,7 @@ fn parse_args() -> io::Result<Config> {
                     .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput,
                         "Invalid number for --max-block-splits"))?;
                 max_block_splits = Some(val);
-                compression_level = CompressionLevel::Custom;
+                choose_level(&mut level, &args[i - 1], CompressionLevel::Custom)?;
             }
             "--block-type" => {
                 i += 1;
@@ -188,18 +731,173 @@ fn parse_args() -> io::Result<Config> {
                             "Block type must be 'dynamic' or 'fixed'"));
                     }
                 };
-                compression_level = CompressionLevel::Custom;
+                choose_level(&mut level, &args[i - 1], CompressionLevel::Custom)?;
+            }
+            "--threads" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                        "Missing value for --threads"));
+                }
+                threads = match args[i].parse::<usize>() {
+                    Ok(0) => thread::available_parallelism().map_or(1, |n| n.get()),
+                    Ok(n) => n,
+                    Err(_) => return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                        "Invalid number for --threads")),
+                };
+            }
+            "--encrypt" => encrypt = true,
+            "--cache" => cache = true,
+            // The last one wins, so that an alias adding --cache can be undone
+            "--no-cache" => cache = false,
+            "--extract-only" | "--no-exec" => extract_only = true,
+            "--data" => data = true,
+            "--no-xattr" | "--no-xattrs" => no_xattr = true,
+            "--no-sparse" => no_sparse = true,
+            "--max-output" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                        "Missing value for --max-output"));
+                }
+                max_output = Some(parse_size(&args[i]).ok_or_else(|| io::Error::new(
+                    io::ErrorKind::InvalidInput, "Invalid size for --max-output (e.g. 4096, 64M, 2G)"))?);
+            }
+            "--cksum-check" => cksum_check = true,
+            "-r" | "--recursive" => recursive = true,
+            "--output-dir" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                        "Missing value for --output-dir"));
+                }
+                output_dir = Some(PathBuf::from(&args[i]));
+            }
+            "--bundle" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                        "Missing value for --bundle"));
+                }
+                bundle = Some(PathBuf::from(&args[i]));
+            }
+            "--entry" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                        "Missing value for --entry"));
+                }
+                entry = Some(args[i].clone());
+            }
+            "-f" | "--force" => force = true,
+            "--fail-fast" => fail_fast = true,
+            "--keep-going" => fail_fast = false,
+            "--backup-suffix" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                        "Missing value for --backup-suffix"));
+                }
+                if args[i].is_empty() || args[i].contains('/') {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                        "--backup-suffix must be a non-empty file name suffix"));
+                }
+                backup_suffix = Some(args[i].clone());
+            }
+            "--wait" => wait = true,
+            "--allow-setuid" => allow_setuid = true,
+            "--preserve-caps" => preserve_caps = true,
+            "--any" => any = true,
+            "--dereference" => dereference = true,
+            "--strip" => strip = true,
+            "--min-ratio" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                        "Missing value for --min-ratio"));
+                }
+                let val = args[i].trim_end_matches('%').parse::<f64>()
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput,
+                        "Invalid number for --min-ratio"))?;
+                if !(0.0..=100.0).contains(&val) {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                        "--min-ratio must be between 0 and 100"));
+                }
+                min_ratio = Some(val);
+            }
+            "--sign" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                        "Missing value for --sign"));
+                }
+                sign_key = Some(PathBuf::from(&args[i]));
+            }
+            "--gpg-sign" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                        "Missing value for --gpg-sign"));
+                }
+                gpg_key = Some(args[i].clone());
+            }
+            "--verify-sig" => verify_sig = true,
+            "--compare" => compare = true,
+            "--compare-with" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                        "Missing value for --compare-with"));
+                }
+                compare_with = Some(PathBuf::from(&args[i]));
+            }
+            "--pubkey" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                        "Missing value for --pubkey"));
+                }
+                pubkey = Some(PathBuf::from(&args[i]));
+            }
+            "--password-file" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                        "Missing value for --password-file"));
+                }
+                password_file = Some(PathBuf::from(&args[i]));
+            }
+            "--include" | "--exclude" => {
+                let option = args[i].as_str();
+                i += 1;
+                if i >= args.len() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                        format!("Missing value for {}", option)));
+                }
+                if option == "--include" {
+                    include.push(args[i].clone());
+                } else {
+                    exclude.push(args[i].clone());
+                }
             }
             "-v" | "--verbose" => verbose = true,
-            "-h" | "--help" => {
-                print_help(&args[0]);
-                process::exit(0);
+            "-h" | "--help" => return Ok(Action::Help),
+            "--selftest" => return Ok(Action::SelfTest),
+            "--completions" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                        "Missing value for --completions"));
+                }
+                if !["bash", "zsh", "fish"].contains(&args[i].as_str()) {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                        "--completions must be bash, zsh or fish"));
+                }
+                return Ok(Action::Completions(args[i].clone()));
             }
             "-V" | "--version" => {
-                println!("zexe version 0.2.0 (Zopfli)");
-                println!("Author: {} ({}) {}", AUTHOR, YEAR, WEBSITE);
-                println!("Compression levels: fast, normal (default), maximum, ultra");
-                process::exit(0);
+                let verbose = args.iter().any(|arg| arg == "-v" || arg == "--verbose");
+                return Ok(Action::Version { verbose });
             }
             arg if arg.starts_with('-') => {
                 return Err(io::Error::new(io::ErrorKind::InvalidInput,
@@ -210,61 +908,503 @@ fn parse_args() -> io::Result<Config> {
         i += 1;
     }
 
-    if files.is_empty() {
+    if files.is_empty() && !gc {
         return Err(io::Error::new(io::ErrorKind::InvalidInput,
             "No files specified"));
     }
 
-    Ok(Config {
+    if (store || auto) && !level_map.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+            "--map cannot be combined with --store or --auto"));
+    }
+
+    if store && (auto || level.is_some()) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+            "--store cannot be combined with compression levels or --auto"));
+    }
+
+    if output_dir.is_some() && (decompress || info || test || verify_sig || compare || benchmark) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+            "--output-dir only applies when packing"));
+    }
+
+    if json && (info || test || verify_sig || compare || benchmark) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+            "--json only applies when packing or unpacking"));
+    }
+
+    if compare_with.is_some() && (!compare || files.len() != 1) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+            "--compare-with only applies with --compare and a single file"));
+    }
+
+    if entry.is_some() && bundle.is_none() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+            "--entry only applies with --bundle"));
+    }
+
+    // One tar of executables run from a temporary directory: none of the
+    // per-file modes apply
+    if bundle.is_some() && (decompress || info || test || verify_sig || compare || benchmark || json
+                            || output_dir.is_some()
+                            || encrypt || cache || extract_only || data || store || auto || strip
+                            || !level_map.is_empty() || sign_key.is_some() || gpg_key.is_some()) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+            "--bundle only combines with compression levels, --threads, --any, -r and --force"));
+    }
+
+    if !recursive && (!include.is_empty() || !exclude.is_empty()) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+            "--include and --exclude only apply with --recursive"));
+    }
+
+    // A data file is only ever written out
+    extract_only |= data;
+
+    if !default_args.is_empty() && (extract_only || bundle.is_some()) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+            "--args only applies to programs run by the packed file (not --extract-only, --data or --bundle)"));
+    }
+
+    if cache && extract_only {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+            "--cache cannot be combined with --extract-only"));
+    }
+
+    if cache && encrypt {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+            "--cache cannot be combined with --encrypt (it would keep the decrypted program on disk)"));
+    }
+
+    Ok(Action::Run(Box::new(Config {
         decompress,
+        benchmark,
+        gc,
+        info,
+        test,
+        dry_run,
+        json,
         files,
-        compression_level,
+        compression_level: level.map_or_else(default_level, |(_, level)| level),
+        level_map,
+        default_args,
+        auto,
+        store,
         iterations,
         iterations_without_improvement,
         max_block_splits,
         block_type,
+        threads,
+        encrypt,
+        cache,
+        extract_only,
+        data,
+        no_xattr,
+        no_sparse,
+        max_output,
+        cksum_check,
+        recursive,
+        output_dir,
+        bundle,
+        entry,
+        include,
+        exclude,
+        force,
+        fail_fast,
+        backup_suffix,
+        wait,
+        allow_setuid,
+        preserve_caps,
+        any,
+        dereference,
+        min_ratio,
+        strip,
+        sign_key,
+        gpg_key,
+        verify_sig,
+        compare,
+        compare_with,
+        pubkey,
+        password_file,
         verbose,
-    })
+    })))
 }
 
-fn print_help(program: &str) {
-    println!("zexe - Self-extracting executable compressor");
-    println!("Author: {} ({}) {}", AUTHOR, YEAR, WEBSITE);
-    println!();
-    println!("Usage: {} [OPTIONS] file...", program);
-    println!();
-    println!("Options:");
-    println!("  -d                    Decompress the file");
-    println!("  -1, --fast            Fast compression (lower ratio)");
-    println!("  -2, --normal          Normal compression (default)");
-    println!("  -3, --maximum          Maximum compression");
-    println!("  -4, --ultra            Ultra compression (very slow)");
-    println!("  --custom               Use custom compression parameters");
-    println!("  --iterations N         Number of iterations (default varies)");
-    println!("  --iter-without-improvement N");
-    println!("                         Stop after N iterations without improvement");
-    println!("  --max-block-splits N   Maximum number of block splits");
-    println!("  --block-type TYPE      Block type: dynamic or fixed");
-    println!("  -v, --verbose           Verbose output");
-    println!("  -h, --help             Show this help");
-    println!("  -V, --version          Show version");
-    println!();
-    println!("Compression levels:");
-    println!("  fast:    15 iterations, 3 without improvement, 15 splits");
-    println!("  normal:  30 iterations, 5 without improvement, 25 splits (default)");
-    println!("  maximum: 75 iterations, 12 without improvement, 50 splits");
-    println!("  ultra:   200 iterations, 30 without improvement, 100 splits");
-    println!();
-    println!("Examples:");
-    println!("  {} myprogram            # Compress with normal settings", program);
-    println!("  {} --ultra myprogram    # Maximum compression", program);
-    println!("  {} -d myprogram         # Decompress", program);
-    println!("  {} --iterations 100 --max-block-splits 75 myprogram", program);
+// Level used when none is given on the command line: $ZEXE_LEVEL, else normal
+fn default_level() -> CompressionLevel {
+    match env::var(LEVEL_ENV) {
+        Ok(name) => CompressionLevel::from_name(&name).unwrap_or_else(|| {
+            eprintln!("Warning: ignoring {}={}, not a compression level", LEVEL_ENV, name);
+            CompressionLevel::Normal
+        }),
+        Err(_) => CompressionLevel::Normal,
+    }
 }
 
-fn get_compression_options(config: &Config) -> Options {
-    match config.compression_level {
-        CompressionLevel::Fast => {
+// Records the level selected by `option`. Presets and custom parameters
+// are alternatives, so naming two different levels is an error instead of
+// the last one silently winning.
+fn choose_level(chosen: &mut Option<(String, CompressionLevel)>, option: &str,
+                level: CompressionLevel) -> io::Result<()> {
+    match chosen {
+        Some((previous, previous_level)) if *previous_level != level => {
+            Err(io::Error::new(io::ErrorKind::InvalidInput,
+                format!("Conflicting compression levels: {} and {}", previous, option)))
+        }
+        Some(_) => Ok(()),
+        None => {
+            *chosen = Some((option.to_string(), level));
+            Ok(())
+        }
+    }
+}
+
+// Packs a small script with each kind of payload, runs the packed file (so
+// the target tools are exercised too) and unpacks it again, in a private
+// directory under $TMPDIR
+fn self_test(program: &str) -> io::Result<()> {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().subsec_nanos();
+    let dir = env::temp_dir().join(format!("zexe-selftest-{}-{}", process::id(), nanos));
+    fs::create_dir(&dir)?;
+    let result = self_test_in(&dir, program);
+    fs::remove_dir_all(&dir)?;
+    result
+}
+
+fn self_test_in(dir: &Path, program: &str) -> io::Result<()> {
+    let password = dir.join("password");
+    fs::write(&password, "zexe selftest\n")?;
+    let password = password.to_string_lossy().into_owned();
+
+    let has_openssl = Command::new("openssl").arg("version").output().is_ok();
+    let modes: [(&str, &[&str]); 4] = [
+        ("zopfli", &["-1"]),
+        ("stored", &["--store"]),
+        ("checked", &["-1", "--cksum-check"]),
+        ("encrypted", &["-1", "--encrypt", "--password-file", &password]),
+    ];
+
+    let mut failed = 0;
+    for (name, options) in modes {
+        if name == "encrypted" && !has_openssl {
+            println!("  {:<10} SKIP (openssl not found)", name);
+            continue;
+        }
+        let path = dir.join(name);
+        let mut list = vec![program.to_string(), "--any".to_string()];
+        list.extend(options.iter().map(|option| option.to_string()));
+        list.push(path.to_string_lossy().into_owned());
+        match self_test_mode(&path, &list, &password) {
+            Ok(()) => println!("  {:<10} PASS", name),
+            Err(e) => {
+                println!("  {:<10} FAIL: {}", name, e);
+                failed += 1;
+            }
+        }
+    }
+
+    if failed > 0 {
+        return Err(io::Error::other(format!("self-test failed in {} mode(s)", failed)));
+    }
+    Ok(())
+}
+
+fn self_test_mode(path: &Path, args: &[String], password: &str) -> io::Result<()> {
+    let Action::Run(config) = parse_args(args)? else {
+        unreachable!("self-test arguments always describe a run");
+    };
+    let script = b"#!/bin/sh\necho \"zexe selftest $1\"\n";
+    fs::write(path, script)?;
+    #[cfg(unix)]
+    fs::set_permissions(path, fs::Permissions::from_mode(0o755))?;
+
+    compress_file(path, &config)?;
+    let output = Command::new(path).arg("ok").env(PASS_FILE_ENV, password).output()?;
+    if output.stdout != b"zexe selftest ok\n" {
+        return Err(io::Error::other(format!("packed file printed {:?}, error: {}",
+            String::from_utf8_lossy(&output.stdout), String::from_utf8_lossy(&output.stderr).trim())));
+    }
+    decompress_file(path, &config)?;
+    if fs::read(path)? != script {
+        return Err(io::Error::other("round trip changed the file"));
+    }
+    Ok(())
+}
+
+// A single "zexe X.Y.Z" line that scripts can compare
+fn print_version(verbose: bool) {
+    println!("zexe {}", VERSION);
+    if !verbose {
+        return;
+    }
+    println!("Author: {} ({}) {}", AUTHOR, YEAR, WEBSITE);
+    println!("Compression levels: fast, normal (default), maximum, ultra");
+    // Nothing is embedded: packed files rely on tools of the target system
+    println!("Decompressor: none embedded, packed files use gzip, pigz or busybox gzip");
+    println!("Encryption and signatures: openssl ({}, Ed25519)", CIPHER);
+}
+
+fn print_help(program: &str) {
+    println!("zexe - Self-extracting executable compressor");
+    println!("Author: {} ({}) {}", AUTHOR, YEAR, WEBSITE);
+    println!();
+    println!("Usage: {} [OPTIONS] file...", program);
+    println!();
+    println!("Options:");
+    println!("  -d                    Decompress the file (a plain FILE.gz is unpacked to FILE,");
+    println!("                         keeping FILE.gz)");
+    println!("  --benchmark            Rank the compression levels and storing by size, with");
+    println!("                         compression and decompression times; modifies nothing");
+    println!("  -n, --dry-run          Report sizes and ratio without modifying files");
+    println!("  --json                 Print one JSON object per file and a summary on stdout");
+    println!("                         (progress goes to stderr)");
+    println!("  --gc                   Remove temporary directories left by crashed runs");
+    println!("  -l, --info             Show how a compressed file is laid out");
+    println!("  -t, --test             Unpack compressed files in memory and check them against");
+    println!("                         their recorded checksum, writing nothing");
+    println!("  -1, --fast            Fast compression (lower ratio)");
+    println!("  -2, --normal          Normal compression (default, also --balanced)");
+    println!("  -3, --maximum          Maximum compression (also --max)");
+    println!("  -4, --ultra            Ultra compression (very slow)");
+    println!("  --compress-level NAME  fast, normal, balanced, maximum, max, ultra or 1-4");
+    println!("                         (one level only: presets and custom parameters conflict)");
+    println!("  --map EXT=LEVEL        Use LEVEL for files ending in .EXT, whatever the level");
+    println!("                         chosen otherwise (repeatable, e.g. --map so=ultra)");
+    println!("  --auto                 Try every level in parallel and keep the smallest");
+    println!("                         (or store the file as is if nothing is saved)");
+    println!("  -0, --store            Keep the wrapper but do not compress the payload");
+    println!("  --custom               Use custom compression parameters");
+    println!("  --iterations N         Number of iterations (default varies)");
+    println!("  --iter-without-improvement N");
+    println!("                         Stop after N iterations without improvement");
+    println!("  --max-block-splits N   Maximum number of block splits");
+    println!("  --block-type TYPE      Block type: dynamic or fixed");
+    println!("  --threads N            Compress N chunks in parallel (0: one per CPU), at the");
+    println!("                         cost of a slightly larger output; reads the whole file.");
+    println!("                         With -d, unpack N files at a time");
+    println!("  --encrypt              Encrypt the payload with a passphrase (requires openssl)");
+    println!("  --password-file FILE   Read the passphrase from the first line of FILE");
+    println!("  --cache                Extract once to ~/.cache/zexe and reuse it on later runs");
+    println!("                         (keyed and checked by the cksum of the original)");
+    println!("  --no-cache             Extract to a fresh temporary directory on every run");
+    println!("                         (default; cancels an earlier --cache)");
+    println!("  --extract-only         Make the output write the program to a file instead of");
+    println!("                         running it (to its argument, default ./<original name>);");
+    println!("                         also --no-exec");
+    println!("  --data                 Pack any file, executable or not, as a self-extracting");
+    println!("                         archive (implies --extract-only; -d restores its mode)");
+    println!("  --args \"ARGS\"          Run the program with ARGS before the arguments given to");
+    println!("                         the packed file (repeatable). ARGS is split into words");
+    println!("                         as sh would, so quote a word with spaces: \"-m 'a b'\"");
+    println!("  --cksum-check          Make the output check the extracted program with cksum");
+    println!("                         before running it (always done with --cache and --store)");
+    println!("  --strip                Pack a copy stripped of symbols with strip(1); the");
+    println!("                         backup keeps them (ELF and Mach-O only)");
+    println!("  --min-ratio P          Leave files alone unless packing saves at least P%");
+    println!("  --no-xattr(s)          Do not store and restore extended attributes");
+    println!("  --no-sparse            With -d, write zero blocks instead of leaving holes");
+    println!("  --max-output SIZE      With -d, stop unpacking past SIZE bytes (K, M, G suffixes);");
+    println!("                         the size recorded when packing is always enforced");
+    println!("  -r, --recursive        Process the files found in directories, skipping");
+    println!("                         symlinks and files the mode does not apply to");
+    println!("  --output-dir DIR       Write packed files under DIR (keeping paths below the");
+    println!("                         directories given with -r) and leave the inputs alone;");
+    println!("                         inputs that would share an output are refused");
+    println!("  --bundle OUT           Pack all the files into one program OUT that runs the one");
+    println!("                         named like argv[0] (symlink OUT under each name), else");
+    println!("                         the entry point; -d OUT writes them next to it (needs tar)");
+    println!("  --entry NAME           With --bundle, the file run by default (default: the first)");
+    println!("  --include GLOB         With -r, only process paths matching GLOB (repeatable)");
+    println!("  --exclude GLOB         With -r, skip paths matching GLOB (wins over --include)");
+    println!("  -f, --force            Replace an existing backup (file~) or output, pack");
+    println!("                         hard-linked files (the other names are left unpacked),");
+    println!("                         files with Linux capabilities, and re-pack packed files");
+    println!("                         with the options given");
+    println!("  --fail-fast            Start no file after the first one fails; with -d and");
+    println!("                         --threads, files already being unpacked finish and are");
+    println!("                         reported");
+    println!("  --keep-going           Carry on with the other files after a failure (default)");
+    println!("  --backup-suffix S      Keep the original as file + S (e.g. .orig) instead of");
+    println!("                         replacing its extension with ~");
+    println!("  --wait                 If the file is busy (running), retry for up to {}s",
+             BUSY_TIMEOUT.as_secs());
+    println!("  --allow-setuid         Pack setuid/setgid files, keeping the bits for -d");
+    println!("  --preserve-caps        Pack files with Linux capabilities (security.capability),");
+    println!("                         keeping them for -d even with --no-xattr");
+    println!("  --any                  Also pack executables that are not ELF, Mach-O or PE");
+    println!("                         binaries (shell scripts and the like)");
+    println!("  --dereference          Pack or unpack the target of a symlink in place");
+    println!("                         (symlinks are refused otherwise)");
+    println!("  --sign KEY             Sign the payload with an Ed25519 private key (PEM);");
+    println!("                         the output refuses to run if the signature fails.");
+    println!("                         The public key travels in the file, so this catches");
+    println!("                         damage, not a payload re-signed with another key");
+    println!("  --gpg-sign KEYID       Also write a detached gpg signature to file.sig");
+    println!("  --verify-sig           Check the signatures of compressed files (embedded");
+    println!("                         and file.sig)");
+    println!("  --pubkey FILE          With --verify-sig, require this public key (PEM); without");
+    println!("                         it the signer is not authenticated");
+    println!("  --compare              Unpack compressed files in memory and check they match");
+    println!("                         their backup byte for byte (exit status {} if not)",
+             EXIT_DAMAGED);
+    println!("  --compare-with FILE    With --compare, check against FILE instead");
+    println!("  -v, --verbose           Verbose output");
+    println!("  -h, --help             Show this help");
+    println!("  -V, --version          Show version (with -v, also how packed files run)");
+    println!("  --completions SHELL    Print a completion script for bash, zsh or fish");
+    println!("  --selftest             Pack, run and unpack a small script in each payload mode");
+    println!("                         to check this build against the tools of this host");
+    println!();
+    println!("Compression levels:");
+    println!("  fast:    15 iterations, 3 without improvement, 15 splits");
+    println!("  normal:  30 iterations, 5 without improvement, 25 splits (default)");
+    println!("  maximum: 75 iterations, 12 without improvement, 50 splits");
+    println!("  ultra:   200 iterations, 30 without improvement, 100 splits");
+    println!();
+    println!("Encryption:");
+    println!("  The payload is encrypted with openssl ({}, PBKDF2 with {} iterations).",
+             CIPHER, PBKDF2_ITERATIONS);
+    println!("  The passphrase is read from --password-file, or ${} if set, otherwise prompted", PASS_ENV);
+    println!("  on the terminal. The compressed program reads the file named by ${}, or", PASS_FILE_ENV);
+    println!("  ${}, otherwise it prompts too.", PASS_ENV);
+    println!();
+    println!("Environment:");
+    println!("  {}             Default compression level (same names as --compress-level);", LEVEL_ENV);
+    println!("                         any level given on the command line wins");
+    println!();
+    println!("Exit status:");
+    println!("  0 success, {} failures of different kinds, {} bad usage,", EXIT_ERROR, EXIT_USAGE);
+    println!("  {} file not found or refused (already packed, not executable, ...),", EXIT_REFUSED);
+    println!("  {} compression or I/O error, {} damaged file, failed signature/passphrase check", EXIT_IO,
+             EXIT_DAMAGED);
+    println!("  or file that differs from its backup (--compare)");
+    println!();
+    println!("Examples:");
+    println!("  {} myprogram            # Compress with normal settings", program);
+    println!("  {} --ultra myprogram    # Maximum compression", program);
+    println!("  {} -d myprogram         # Decompress", program);
+    println!("  {} --encrypt myprogram  # Compress and encrypt", program);
+    println!("  {} --iterations 100 --max-block-splits 75 myprogram", program);
+}
+
+// What an option expects, for the completion scripts
+#[derive(Debug, Clone, Copy)]
+enum OptionValue {
+    None,
+    File,
+    Dir,
+    Words(&'static str),
+    Text,
+}
+
+// Every option parse_args accepts (test_completion_options checks it)
+const OPTIONS: &[(&str, OptionValue)] = &[
+    ("-d", OptionValue::None), ("--benchmark", OptionValue::None), ("--gc", OptionValue::None),
+    ("-l", OptionValue::None), ("--info", OptionValue::None),
+    ("-t", OptionValue::None), ("--test", OptionValue::None),
+    ("-n", OptionValue::None), ("--dry-run", OptionValue::None), ("--json", OptionValue::None),
+    ("-0", OptionValue::None), ("--store", OptionValue::None),
+    ("-1", OptionValue::None), ("--fast", OptionValue::None),
+    ("-2", OptionValue::None), ("--normal", OptionValue::None), ("--balanced", OptionValue::None),
+    ("-3", OptionValue::None), ("--maximum", OptionValue::None), ("--max", OptionValue::None),
+    ("-4", OptionValue::None), ("--ultra", OptionValue::None),
+    ("--compress-level", OptionValue::Words("fast normal balanced maximum max ultra")),
+    ("--map", OptionValue::Text), ("--args", OptionValue::Text), ("--auto", OptionValue::None), ("--custom", OptionValue::None),
+    ("--iterations", OptionValue::Text), ("--iter-without-improvement", OptionValue::Text),
+    ("--max-block-splits", OptionValue::Text), ("--block-type", OptionValue::Words("dynamic fixed")),
+    ("--threads", OptionValue::Text),
+    ("--encrypt", OptionValue::None), ("--password-file", OptionValue::File),
+    ("--cache", OptionValue::None), ("--no-cache", OptionValue::None),
+    ("--extract-only", OptionValue::None), ("--no-exec", OptionValue::None), ("--data", OptionValue::None),
+    ("--strip", OptionValue::None), ("--min-ratio", OptionValue::Text),
+    ("--no-xattr", OptionValue::None), ("--no-xattrs", OptionValue::None), ("--no-sparse", OptionValue::None),
+    ("--max-output", OptionValue::Text),
+    ("--cksum-check", OptionValue::None),
+    ("-r", OptionValue::None), ("--recursive", OptionValue::None),
+    ("--output-dir", OptionValue::Dir), ("--bundle", OptionValue::File), ("--entry", OptionValue::Text), ("--include", OptionValue::Text), ("--exclude", OptionValue::Text),
+    ("-f", OptionValue::None), ("--force", OptionValue::None),
+    ("--fail-fast", OptionValue::None), ("--keep-going", OptionValue::None), ("--backup-suffix", OptionValue::Text),
+    ("--wait", OptionValue::None), ("--allow-setuid", OptionValue::None), ("--preserve-caps", OptionValue::None),
+    ("--any", OptionValue::None), ("--dereference", OptionValue::None),
+    ("--sign", OptionValue::File), ("--gpg-sign", OptionValue::Text),
+    ("--verify-sig", OptionValue::None), ("--pubkey", OptionValue::File),
+    ("--compare", OptionValue::None), ("--compare-with", OptionValue::File),
+    ("-v", OptionValue::None), ("--verbose", OptionValue::None),
+    ("-h", OptionValue::None), ("--help", OptionValue::None),
+    ("-V", OptionValue::None), ("--version", OptionValue::None),
+    ("--completions", OptionValue::Words("bash zsh fish")), ("--selftest", OptionValue::None),
+];
+
+fn print_completions(shell: &str) {
+    match shell {
+        "bash" => {
+            println!("_zexe() {{");
+            println!("    local cur=${{COMP_WORDS[COMP_CWORD]}} prev=${{COMP_WORDS[COMP_CWORD-1]}}");
+            println!("    case $prev in");
+            for (name, value) in OPTIONS {
+                match value {
+                    OptionValue::None => {}
+                    OptionValue::File => println!("        {}) COMPREPLY=($(compgen -f -- \"$cur\")); return;;", name),
+                    OptionValue::Dir => println!("        {}) COMPREPLY=($(compgen -d -- \"$cur\")); return;;", name),
+                    OptionValue::Words(words) => {
+                        println!("        {}) COMPREPLY=($(compgen -W \"{}\" -- \"$cur\")); return;;", name, words)
+                    }
+                    OptionValue::Text => println!("        {}) return;;", name),
+                }
+            }
+            println!("    esac");
+            println!("    case $cur in");
+            let names: Vec<&str> = OPTIONS.iter().map(|(name, _)| *name).collect();
+            println!("        -*) COMPREPLY=($(compgen -W \"{}\" -- \"$cur\"));;", names.join(" "));
+            println!("        *) COMPREPLY=($(compgen -f -- \"$cur\"));;");
+            println!("    esac");
+            println!("}}");
+            println!("complete -o filenames -F _zexe zexe");
+        }
+        "zsh" => {
+            println!("#compdef zexe");
+            println!("_arguments \\");
+            for (name, value) in OPTIONS {
+                let action = match value {
+                    OptionValue::None => String::new(),
+                    OptionValue::File => ":file:_files".to_string(),
+                    OptionValue::Dir => ":directory:_files -/".to_string(),
+                    OptionValue::Words(words) => format!(":value:({})", words),
+                    OptionValue::Text => ":value: ".to_string(),
+                };
+                println!("    '{}{}' \\", name, action);
+            }
+            println!("    '*:file:_files'");
+        }
+        _ => {
+            for (name, value) in OPTIONS {
+                let flag = match name.strip_prefix("--") {
+                    Some(long) => format!("-l {}", long),
+                    None => format!("-s {}", &name[1..]),
+                };
+                let value = match value {
+                    OptionValue::None => String::new(),
+                    OptionValue::File | OptionValue::Dir => " -r -F".to_string(),
+                    OptionValue::Words(words) => format!(" -x -a '{}'", words),
+                    OptionValue::Text => " -x".to_string(),
+                };
+                println!("complete -c zexe {}{}", flag, value);
+            }
+        }
+    }
+}
+
+// The --map entry for the extension of `path`, else the chosen level
+fn file_level(path: &Path, config: &Config) -> CompressionLevel {
+    let ext = path.extension().map(|ext| ext.to_string_lossy());
+    config.level_map.iter()
+        .find(|(mapped, _)| ext.as_deref() == Some(mapped.as_str()))
+        .map_or(config.compression_level, |&(_, level)| level)
+}
+
+fn level_options(level: CompressionLevel, config: &Config) -> Options {
+    match level {
+        CompressionLevel::Fast => {
             Options {
                 iteration_count: NonZeroU64::new(15).unwrap(),
                 iterations_without_improvement: NonZeroU64::new(3).unwrap(),
@@ -318,212 +1458,4247 @@ fn is_compressed(path: &Path) -> io::Result<bool> {
     Ok(magic == MAGIC)
 }
 
-fn check_file(path: &Path) -> io::Result<()> {
+fn detect_binary_format(header: &[u8]) -> Option<BinaryFormat> {
+    let magic: [u8; 4] = header.get(..4)?.try_into().ok()?;
+    match magic {
+        [0x7f, b'E', b'L', b'F'] => Some(BinaryFormat::Elf),
+        [0xfe, 0xed, 0xfa, 0xce] | [0xfe, 0xed, 0xfa, 0xcf] |
+        [0xce, 0xfa, 0xed, 0xfe] | [0xcf, 0xfa, 0xed, 0xfe] => Some(BinaryFormat::MachO),
+        [0xca, 0xfe, 0xba, 0xbe] => Some(BinaryFormat::MachOFat),
+        [b'M', b'Z', _, _] => Some(BinaryFormat::Pe),
+        _ => None,
+    }
+}
+
+fn read_binary_format(path: &Path) -> io::Result<Option<BinaryFormat>> {
+    let mut header = Vec::with_capacity(4);
+    fs::File::open(path)?.take(4).read_to_end(&mut header)?;
+    Ok(detect_binary_format(&header))
+}
+
+// Replaces directories given with --recursive by the files below them that
+// the current mode applies to. Symlinks are never followed.
+fn expand_files(config: &Config) -> io::Result<Vec<Input>> {
+    let mut files = Vec::new();
+    for path in &config.files {
+        if config.recursive && path.is_dir() {
+            walk_dir(path, path, config, &mut files)?;
+        } else {
+            let relative = PathBuf::from(path.file_name().unwrap_or(path.as_os_str()));
+            files.push(Input { path: path.clone(), relative });
+        }
+    }
+    Ok(files)
+}
+
+// Inputs given directly only keep their name below --output-dir, so
+// a/prog and b/prog would be written to the same file
+fn check_output_paths(files: &[Input], dir: &Path) -> io::Result<()> {
+    let mut seen = std::collections::HashMap::new();
+    for Input { path, relative } in files {
+        if let Some(other) = seen.insert(relative, path) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                format!("{} and {} would both be written to {}", other.display(), path.display(),
+                        dir.join(relative).display())));
+        }
+    }
+    Ok(())
+}
+
+// A file to process, and where it goes below --output-dir: its path under
+// the directory being walked, or just its name when given directly
+#[derive(Debug, PartialEq)]
+struct Input {
+    path: PathBuf,
+    relative: PathBuf,
+}
+
+fn walk_dir(root: &Path, dir: &Path, config: &Config, files: &mut Vec<Input>) -> io::Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let path = entry.path();
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            walk_dir(root, &path, config, files)?;
+        } else if file_type.is_file() && !is_backup(&path, config) {
+            let relative = path.strip_prefix(root).unwrap_or(&path).to_path_buf();
+            if !is_selected(&relative.to_string_lossy(), config) {
+                if config.verbose {
+                    eprintln!("{}: skipped by --include/--exclude", path.display());
+                }
+            } else if is_candidate(&path, config) {
+                files.push(Input { path, relative });
+            }
+        }
+    }
+    Ok(())
+}
+
+// --include and --exclude, matched against the path below the directory
+// being walked: excludes win, and no --include means everything
+fn is_selected(relative: &str, config: &Config) -> bool {
+    (config.include.is_empty() || config.include.iter().any(|glob| glob_match(glob, relative)))
+        && !config.exclude.iter().any(|glob| glob_match(glob, relative))
+}
+
+// Shell-style wildcards: * and ? (both also match '/'), and [abc], [a-z],
+// [!x] classes
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Where to resume after the last '*', and how much text it has taken
+    let mut retry = None;
+    while t < text.len() {
+        let step = match pattern.get(p) {
+            Some('*') => {
+                p += 1;
+                retry = Some((p, t));
+                continue;
+            }
+            Some('?') => Some(1),
+            Some('[') => match_class(&pattern[p..], text[t]),
+            Some(&c) => (c == text[t]).then_some(1),
+            None => None,
+        };
+        match (step, retry) {
+            (Some(len), _) => {
+                p += len;
+                t += 1;
+            }
+            (None, Some((star_p, star_t))) => {
+                p = star_p;
+                t = star_t + 1;
+                retry = Some((star_p, t));
+            }
+            (None, None) => return false,
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+// Matches `c` against the class opening `pattern` and returns the class
+// length. A '[' without its ']' is an ordinary character.
+fn match_class(pattern: &[char], c: char) -> Option<usize> {
+    let negate = matches!(pattern.get(1), Some('!') | Some('^'));
+    let start = if negate { 2 } else { 1 };
+    let Some(close) = pattern.iter().skip(start + 1).position(|&ch| ch == ']') else {
+        return (c == '[').then_some(1);
+    };
+    let class = &pattern[start..start + 1 + close];
+    let mut found = false;
+    let mut i = 0;
+    while i < class.len() {
+        if i + 2 < class.len() && class[i + 1] == '-' {
+            found |= class[i] <= c && c <= class[i + 2];
+            i += 3;
+        } else {
+            found |= class[i] == c;
+            i += 1;
+        }
+    }
+    (found != negate).then_some(start + 2 + close)
+}
+
+// Backups made by create_backup are left alone
+fn is_backup(path: &Path, config: &Config) -> bool {
+    match &config.backup_suffix {
+        Some(suffix) => path.as_os_str().as_encoded_bytes().ends_with(suffix.as_bytes()),
+        None => path.extension().is_some_and(|ext| ext == "~"),
+    }
+}
+
+// Where create_backup keeps the original. The default replaces the
+// extension (`tool.sh` -> `tool.~`), as zexe always did; --backup-suffix
+// appends instead (`tool.sh` -> `tool.sh.orig`)
+fn backup_path(path: &Path, config: &Config) -> PathBuf {
+    match &config.backup_suffix {
+        Some(suffix) => {
+            let mut backup = path.as_os_str().to_os_string();
+            backup.push(suffix);
+            PathBuf::from(backup)
+        }
+        None => path.with_extension("~"),
+    }
+}
+
+// Packing picks the files compress_file would accept (executable binaries,
+// setuid only with --allow-setuid); the other modes pick packed files
+fn is_candidate(path: &Path, config: &Config) -> bool {
+    let packed = is_compressed(path).unwrap_or(false);
+    if config.decompress || config.info || config.test || config.verify_sig || config.compare {
+        packed
+    } else if packed {
+        // Re-packed with --force
+        config.force
+    } else {
+        check_file(path, config).is_ok()
+    }
+}
+
+// Architecture from the e_machine field of an ELF header, spelled like
+// std::env::consts::ARCH
+fn elf_machine(header: &[u8]) -> Option<&'static str> {
+    if detect_binary_format(header)? != BinaryFormat::Elf {
+        return None;
+    }
+    let bytes = [*header.get(18)?, *header.get(19)?];
+    let machine = match header.get(5)? {
+        1 => u16::from_le_bytes(bytes),
+        2 => u16::from_be_bytes(bytes),
+        _ => return None,
+    };
+    let is_64 = header.get(4)? == &2;
+    Some(match (machine, is_64) {
+        (3, _) => "x86",
+        (62, _) => "x86_64",
+        (40, _) => "arm",
+        (183, _) => "aarch64",
+        (8, false) => "mips",
+        (8, true) => "mips64",
+        (20, _) => "powerpc",
+        (21, _) => "powerpc64",
+        (22, _) => "s390x",
+        (43, _) => "sparc64",
+        (243, false) => "riscv32",
+        (243, true) => "riscv64",
+        (258, _) => "loongarch64",
+        _ => return None,
+    })
+}
+
+fn read_elf_machine(path: &Path) -> io::Result<Option<&'static str>> {
+    let mut header = Vec::with_capacity(20);
+    fs::File::open(path)?.take(20).read_to_end(&mut header)?;
+    Ok(elf_machine(&header))
+}
+
+// The result replaces `path` by rename, which would turn a symlink into a
+// regular file and leave its target alone, so links are only followed
+// with --dereference
+fn resolve_symlink(path: &Path, config: &Config) -> io::Result<PathBuf> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_symlink() => {}
+        _ => return Ok(path.to_path_buf()),
+    }
+    if !config.dereference {
+        return Err(ZexeError::Symlink.into());
+    }
+    fs::canonicalize(path)
+}
+
+fn check_file(path: &Path, config: &Config) -> io::Result<()> {
     if !path.exists() {
-        return Err(io::Error::new(io::ErrorKind::NotFound,
-            "file does not exist"));
+        return Err(ZexeError::NotFound.into());
     }
 
     if !path.is_file() {
-        return Err(io::Error::new(io::ErrorKind::InvalidInput,
-            "not a regular file"));
+        return Err(ZexeError::NotRegularFile.into());
+    }
+
+    // The header alone would be all there is to run
+    if fs::metadata(path)?.len() == 0 {
+        return Err(ZexeError::Empty.into());
+    }
+
+    // Data files are never run, whatever their bits and contents
+    if config.data {
+        return Ok(());
+    }
+
+    check_permissions(&fs::metadata(path)?, config.allow_setuid)?;
+
+    // The kernel grants file capabilities to the packed script, not to the
+    // copy it extracts and runs
+    if !config.preserve_caps && !config.force && read_xattrs(path)?.iter().any(|(name, _)| name == CAPABILITY_XATTR) {
+        return Err(ZexeError::Capabilities.into());
+    }
+
+    // Wrapping a script in another script rarely saves anything
+    if !config.any && read_binary_format(path)?.is_none() {
+        return Err(ZexeError::NotBinary.into());
     }
 
-    let metadata = fs::metadata(path)?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn check_permissions(metadata: &fs::Metadata, allow_setuid: bool) -> io::Result<()> {
     let permissions = metadata.permissions();
     
     if permissions.mode() & 0o111 == 0 {
-        return Err(io::Error::new(io::ErrorKind::InvalidInput,
-            "not executable"));
+        return Err(ZexeError::NotExecutable.into());
     }
 
-    if metadata.mode() & 0o6000 != 0 {
-        return Err(io::Error::new(io::ErrorKind::InvalidInput,
-            "has setuid/setgid bits set"));
+    if metadata.mode() & 0o6000 != 0 && !allow_setuid {
+        return Err(ZexeError::Setuid.into());
     }
 
     Ok(())
 }
 
-fn compress_file(path: &Path, config: &Config) -> io::Result<Option<FileInfo>> {
-    if is_compressed(path)? {
-        return Err(io::Error::new(io::ErrorKind::AlreadyExists,
-            "file already compressed"));
-    }
-
-    check_file(path)?;
+// No execute or setuid bits outside Unix: files are packed for a Unix target
+// and keep whatever permissions the filesystem reports
+#[cfg(not(unix))]
+fn check_permissions(_metadata: &fs::Metadata, _allow_setuid: bool) -> io::Result<()> {
+    Ok(())
+}
 
-    // Create backup
-    let backup = path.with_extension("~");
-    fs::copy(path, &backup)?;
+// Output of the POSIX `cksum` utility: CRC-32 (polynomial 0x04C11DB7, with
+// the length appended) and size in bytes. Used because `cksum` is available
+// on every system the generated script runs on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Cksum {
+    crc: u32,
+    size: u64,
+}
 
-    // Read original
-    let original_data = fs::read(path)?;
-    let original_size = original_data.len() as u64;
+impl Cksum {
+    fn cache_key(&self) -> String {
+        format!("{:08x}-{}", self.crc, self.size)
+    }
+}
 
-    // Get compression options
-    let options = get_compression_options(config);
-    
-    if config.verbose {
-        eprintln!("Compression settings:");
-        eprintln!("  Iterations: {}", options.iteration_count);
-        eprintln!("  Iterations without improvement: {}", options.iterations_without_improvement);
-        eprintln!("  Max block splits: {}", options.maximum_block_splits);
-        eprintln!("  Block type: {:?}", config.block_type);
+impl std::fmt::Display for Cksum {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} {}", self.crc, self.size)
     }
+}
 
-    // Compress with Zopfli
-    println!("Compressing {} with Zopfli ({} level, this may take a while)...", 
-             path.display(), config.compression_level.as_str());
-    
-    let compressed = compress_zopfli(&original_data, options, config.block_type)?;
-    let compressed_size = compressed.len() as u64;
+// What the header records about the file being packed
+#[derive(Debug)]
+struct Original {
+    name: String,
+    mode: u32,
+    cksum: Cksum,
+    xattrs: Xattrs,
+}
 
-    // Generate header with fixed size
-    let header = format!(
-        r#"#!/bin/sh
-# compressed by zexe (Zopfli)
-# This script is exactly {} bytes long
-tmp=`mktemp -d /tmp/zexe.XXXXXXXXXX` || exit 1
-trap 'rm -rf "$tmp"' 0
-tail -c +{} "$0" | gzip -dc > "$tmp/prog" 2>/dev/null && \
-    chmod u+x "$tmp/prog" && exec "$tmp/prog" "$@"
-exit $?
-"#,
-        HEADER_SIZE, HEADER_SIZE + 1
-    );
-    
-    // Pad header to exactly HEADER_SIZE bytes
-    let mut header_bytes = header.into_bytes();
-    header_bytes.resize(HEADER_SIZE, b'#');
-    header_bytes[HEADER_SIZE - 1] = b'\n';
+impl Original {
+    // `source` holds what gets packed: `path` itself, or a stripped copy
+    fn read(path: &Path, source: &Path) -> io::Result<Original> {
+        // The name ends up on a header comment line, so it must fit on one
+        let name = path.file_name()
+            .map(|n| n.to_string_lossy().replace('\n', "?"))
+            .unwrap_or_else(|| "prog".to_string());
+        #[cfg(unix)]
+        let mode = fs::metadata(path)?.mode() & 0o7777;
+        #[cfg(not(unix))]
+        let mode = 0o644;
+        let cksum = posix_cksum(BufReader::new(fs::File::open(source)?))?;
+        let mut xattrs = read_xattrs(path)?;
+        xattrs.retain(|(name, _)| {
+            let kept = !has_control_chars(name);
+            if !kept {
+                eprintln!("{}: warning: not keeping extended attribute {:?}: its name has control characters",
+                          path.display(), name);
+            }
+            kept
+        });
+        Ok(Original { name, mode, cksum, xattrs })
+    }
+}
 
-    // Create compressed file with header
-    let temp_path = path.with_extension(".tmp");
-    let mut final_file = fs::File::create(&temp_path)?;
-    final_file.write_all(&header_bytes)?;
-    final_file.write_all(&compressed)?;
-    final_file.sync_all()?;
+// Quotes `s` for safe use as a single shell word
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
 
-    // Copy permissions
-    let metadata = fs::metadata(path)?;
-    fs::set_permissions(&temp_path, metadata.permissions())?;
+fn posix_cksum<R: Read>(mut reader: R) -> io::Result<Cksum> {
+    let mut writer = CksumWriter::new();
+    io::copy(&mut reader, &mut writer)?;
+    Ok(writer.finish())
+}
 
-    // Replace original
-    fs::rename(&temp_path, path)?;
+// Computes the cksum of whatever is written to it
+struct CksumWriter {
+    table: [u32; 256],
+    crc: u32,
+    size: u64,
+}
 
-    if config.verbose {
-        eprintln!("Compression complete:");
-        eprintln!("  Original size: {} bytes", original_size);
-        eprintln!("  Compressed size: {} bytes", compressed_size + header_bytes.len() as u64);
-        eprintln!("  Header size: {} bytes", header_bytes.len());
-        eprintln!("  Compression ratio: {:.1}%", 
-                 (original_size - compressed_size) as f64 * 100.0 / original_size as f64);
+impl CksumWriter {
+    fn new() -> CksumWriter {
+        let mut table = [0u32; 256];
+        for (i, entry) in table.iter_mut().enumerate() {
+            let mut c = (i as u32) << 24;
+            for _ in 0..8 {
+                c = if c & 0x8000_0000 != 0 { (c << 1) ^ 0x04c1_1db7 } else { c << 1 };
+            }
+            *entry = c;
+        }
+        CksumWriter { table, crc: 0, size: 0 }
     }
 
-    Ok(Some(FileInfo {
-        path: path.to_path_buf(),
-        original_size,
-        compressed_size: compressed_size + header_bytes.len() as u64,
-    }))
+    fn update(&mut self, byte: u8) {
+        self.crc = (self.crc << 8) ^ self.table[((self.crc >> 24) as u8 ^ byte) as usize];
+    }
+
+    fn finish(mut self) -> Cksum {
+        let mut len = self.size;
+        while len != 0 {
+            self.update(len as u8);
+            len >>= 8;
+        }
+        Cksum { crc: !self.crc, size: self.size }
+    }
 }
 
-fn decompress_file(path: &Path) -> io::Result<Option<FileInfo>> {
-    if !is_compressed(path)? {
-        return Err(io::Error::new(io::ErrorKind::InvalidInput,
-            "file not compressed"));
+impl Write for CksumWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &b in buf {
+            self.update(b);
+        }
+        self.size += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
     }
+}
 
-    let data = fs::read(path)?;
-    let compressed_size = data.len() as u64;
+// Reads the script header of a compressed file, leaving `file` positioned
+// at the start of the payload. Older files always use HEADER_SIZE bytes.
+fn read_header(file: &mut fs::File) -> io::Result<Vec<u8>> {
+    let mut header = Vec::with_capacity(HEADER_SIZE);
+    (&mut *file).take(HEADER_SIZE as u64).read_to_end(&mut header)?;
 
-    if data.len() <= HEADER_SIZE {
+    let size = parse_header_size(&header).unwrap_or(HEADER_SIZE);
+    if header.len() < HEADER_SIZE || size < HEADER_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+            "corrupted compressed file"));
+    }
+    (&mut *file).take((size - HEADER_SIZE) as u64).read_to_end(&mut header)?;
+    if header.len() != size {
         return Err(io::Error::new(io::ErrorKind::InvalidData,
             "corrupted compressed file"));
     }
+    Ok(header)
+}
 
-    // Decompress from HEADER_SIZE (using flate2 for decompression)
-    let mut decoder = GzDecoder::new(&data[HEADER_SIZE..]);
-    let mut decompressed = Vec::new();
-    decoder.read_to_end(&mut decompressed)?;
-    let original_size = decompressed.len() as u64;
+fn parse_header_size(header: &[u8]) -> Option<usize> {
+    header_value(header, b"# This script is exactly ")?
+        .split(' ')
+        .next()?
+        .parse()
+        .ok()
+}
 
-    // Save
-    let temp_path = path.with_extension(".tmp");
-    fs::write(&temp_path, &decompressed)?;
+fn parse_header_cksum(header: &[u8]) -> Option<Cksum> {
+    let mut fields = header_value(header, CKSUM_PREFIX)?.split(' ');
+    let crc = fields.next()?.parse().ok()?;
+    let size = fields.next()?.parse().ok()?;
+    Some(Cksum { crc, size })
+}
 
-    let metadata = fs::metadata(path)?;
-    fs::set_permissions(&temp_path, metadata.permissions())?;
+// Returns the rest of the first header line starting with `prefix`
+fn header_value<'a>(header: &'a [u8], prefix: &'a [u8]) -> Option<&'a str> {
+    header_values(header, prefix).next()
+}
 
-    fs::rename(&temp_path, path)?;
+fn header_values<'a>(header: &'a [u8], prefix: &'a [u8]) -> impl Iterator<Item = &'a str> {
+    header.split(|&b| b == b'\n')
+        .filter_map(move |line| line.strip_prefix(prefix))
+        .filter_map(|value| std::str::from_utf8(value).ok())
+}
 
-    Ok(Some(FileInfo {
-        path: path.to_path_buf(),
-        original_size,
-        compressed_size,
-    }))
+fn has_control_chars(s: &str) -> bool {
+    s.chars().any(char::is_control)
 }
 
-fn compress_zopfli(data: &[u8], options: Options, block_type: BlockType) -> io::Result<Vec<u8>> {
-    let mut compressed = Vec::new();
-    
-    // Créer l'encodeur
-    let mut encoder = GzipEncoder::new(options, block_type, &mut compressed)
-        .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("Zopfli init error: {}", e)))?;
-    
-    // Écriture des données
-    encoder.write_all(data)
-        .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("Zopfli write error: {}", e)))?;
-    
-    // Finalisation
-    encoder.finish()
-        .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("Zopfli finish error: {}", e)))?;
-    
-    Ok(compressed)
+fn parse_header_xattrs(header: &[u8]) -> Xattrs {
+    header_values(header, XATTR_PREFIX)
+        .filter_map(|value| {
+            let (name, encoded) = value.rsplit_once(' ')?;
+            Some((name.to_string(), base64_decode(encoded)?))
+        })
+        .collect()
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::os::unix::fs::PermissionsExt;
+fn header_has(header: &[u8], marker: &[u8]) -> bool {
+    header.windows(marker.len()).any(|w| w == marker)
+}
 
-    #[test]
-    fn test_compress_decompress() -> io::Result<()> {
-        let test_file = env::temp_dir().join("zexe_test");
-        fs::write(&test_file, b"#!/bin/sh\necho 'Hello World'\n")?;
-        
-        let mut perms = fs::metadata(&test_file)?.permissions();
-        perms.set_mode(0o755);
-        fs::set_permissions(&test_file, perms)?;
+fn generate_header(config: &Config, original: &Original, stored: bool,
+                   signature: Option<&Signature>) -> io::Result<Vec<u8>> {
+    // Comment lines describing the payload, read back by -d and --info
+    let mut notes = format!("{}{}\n{}{}\n",
+                            String::from_utf8_lossy(CKSUM_PREFIX), original.cksum,
+                            String::from_utf8_lossy(NAME_PREFIX), original.name);
 
-        let config = Config {
-            decompress: false,
-            files: vec![test_file.clone()],
-            compression_level: CompressionLevel::Normal,
-            iterations: None,
-            iterations_without_improvement: None,
-            max_block_splits: None,
+    // Decryption and signature checks need openssl on the target too
+    let mut need_openssl = if config.encrypt || signature.is_some() {
+        "command -v openssl >/dev/null 2>&1 || { echo \"$0: error: 'openssl' not found; install openssl to run this program\" >&2; exit 1; }\n".to_string()
+    } else {
+        String::new()
+    };
+
+    // Optional decryption stage, fed by the first line of $ZEXE_PASS_FILE,
+    // $ZEXE_PASS or a terminal prompt
+    let decrypt = if config.encrypt {
+        notes += &format!("{} {}\n", String::from_utf8_lossy(ENCRYPTED_MARKER), CIPHER);
+        need_openssl += &format!(
+            "k=; if [ -n \"${{{file}:-}}\" ]; then k=\"file:${file}\"; elif [ -n \"${{{env}+x}}\" ]; then k=env:{env}; fi\n",
+            file = PASS_FILE_ENV, env = PASS_ENV);
+        format!("openssl enc -d -{} -pbkdf2 -iter {} ${{k:+-pass \"$k\"}} | ",
+                CIPHER, PBKDF2_ITERATIONS)
+    } else {
+        String::new()
+    };
+    for (name, value) in &original.xattrs {
+        // A newline in the name would start a line of script
+        if has_control_chars(name) {
+            continue;
+        }
+        if !config.no_xattr || (config.preserve_caps && name == CAPABILITY_XATTR) {
+            notes += &format!("{}{} {}\n", String::from_utf8_lossy(XATTR_PREFIX),
+                              name, base64_encode(value));
+        }
+    }
+    // A stored payload is copied out as is and needs no gzip at all
+    let (find_gzip, inflate) = if stored {
+        notes += &format!("{}\n", String::from_utf8_lossy(STORED_MARKER));
+        ("", "cat")
+    } else {
+        (FIND_GZIP, "$z -dc")
+    };
+    if config.cache {
+        notes += &format!("{}\n", String::from_utf8_lossy(CACHED_MARKER));
+    }
+    if config.extract_only {
+        notes += &format!("{}\n", String::from_utf8_lossy(EXTRACT_ONLY_MARKER));
+    }
+    // The packed file gains u+x, so -d needs the mode of a data file
+    if config.data {
+        notes += &format!("{}{:o}\n", String::from_utf8_lossy(MODE_PREFIX), original.mode);
+    }
+    // Baked in ahead of "$@", each one quoted as a single word
+    let default_args: String = config.default_args.iter().map(|arg| shell_quote(arg) + " ").collect();
+    if !default_args.is_empty() {
+        notes += &format!("{}{}\n", String::from_utf8_lossy(ARGS_PREFIX), default_args.trim_end());
+    }
+    if let Some(signature) = signature {
+        notes += &format!("{}{}\n{}{}\n",
+                          String::from_utf8_lossy(SIGNER_PREFIX), signature.public_key,
+                          String::from_utf8_lossy(SIGNATURE_PREFIX), signature.value);
+    }
+
+    // gzip checks its CRC while inflating, a stored payload has nothing, so
+    // it always gets the cksum the cache mode uses; --cksum-check adds it to
+    // the other modes too
+    let check = |file: &str| if config.cksum_check || stored {
+        format!(r#" && {{ [ "`cksum < "${file}"`" = "{cksum}" ] || {{ echo "$0: checksum mismatch (corrupted file?)" >&2; false; }}; }}"#,
+                file = file, cksum = original.cksum)
+    } else {
+        String::new()
+    };
+
+    fit_header(|size| {
+        // A signed payload is extracted from the copy that was verified,
+        // not read from "$0" a second time
+        let source = if signature.is_some() {
+            r#"{ cat "$v/payload"; rm -rf "$v"; }"#.to_string()
+        } else {
+            format!(r#"tail -c +{} "$0""#, size + 1)
+        };
+        let extract = if config.extract_only {
+            // Write the program next to the caller instead of running it,
+            // never replacing an existing file
+            format!(
+                r#"o=${{1:-{name}}}
+[ -d "$o" ] && o="$o/"{name}
+[ -e "$o" ] && {{ echo "$0: $o already exists" >&2; exit 1; }}
+{source} | {decrypt}{inflate} > "$o" 2>/dev/null{check}{chmod} || {{ rm -f "$o"; exit 1; }}
+echo "$0: extracted to $o"
+exit 0
+"#,
+                name = shell_quote(&original.name), source = source, decrypt = decrypt, inflate = inflate,
+                check = check("o"), chmod = if config.data { "" } else { r#" && chmod u+x "$o""# })
+        } else if config.cache {
+            // Extract once into the cache under the original name, keyed
+            // and verified by the POSIX cksum of the original; concurrent
+            // runs each write their own temp file and atomically rename it
+            // into place
+            format!(
+                r#"c="${{XDG_CACHE_HOME:-$HOME/.cache}}/zexe/{key}"; p="$c/"{name}
+if [ "`cksum 2>/dev/null < "$p"`" != "{cksum}" ]; then
+    mkdir -p "$c" && tmp=`mktemp "$c/.zexe.XXXXXXXXXX"` || exit 1
+    {source} | {decrypt}{inflate} > "$tmp" 2>/dev/null && chmod 700 "$tmp" && \
+        [ "`cksum < "$tmp"`" = "{cksum}" ] && mv -f "$tmp" "$p" || {{ rm -f "$tmp"; exit 1; }}
+fi
+{cleanup}exec "$p" {args}"$@"
+exit $?
+"#,
+                key = original.cksum.cache_key(), name = shell_quote(&original.name),
+                cksum = original.cksum, source = source, decrypt = decrypt, inflate = inflate,
+                args = default_args,
+                // Nothing was extracted on a cache hit
+                cleanup = if signature.is_some() { "rm -rf \"$v\"\n" } else { "" })
+        } else {
+            // The program keeps its original file name so that argv[0]
+            // based dispatch still works. exec replaces the shell before
+            // its EXIT trap can run, so
+            // removal is deferred to a detached job (as gzexe does) and
+            // directories left by killed runs are swept after a day (the
+            // find expression sticks to POSIX: no -maxdepth or -mmin)
+            format!(
+                r#"d="${{TMPDIR:-/tmp}}"
+find "$d/." ! -name . -prune -type d -name '{prefix}??????????' -mtime +{age} -exec rm -rf {{}} + 2>/dev/null
+tmp=`mktemp -d "$d/{prefix}XXXXXXXXXX"` || exit 1
+trap 'rm -rf "$tmp"' 0
+p="$tmp/"{name}
+{source} | {decrypt}{inflate} > "$p" 2>/dev/null{check} && chmod u+x "$p" && {{
+    (sleep 5; rm -rf "$tmp") >/dev/null 2>&1 </dev/null &
+    exec "$p" {args}"$@"; }}
+exit $?
+"#,
+                prefix = TEMP_PREFIX, age = STALE_TEMP_AGE.as_secs() / 86400 - 1,
+                name = shell_quote(&original.name), source = source, decrypt = decrypt, inflate = inflate,
+                check = check("p"), args = default_args)
+        };
+
+        // Refuse to run a payload that does not match its signature
+        let verify = match signature {
+            Some(signature) => format!(
+                r#"v=`mktemp -d "${{TMPDIR:-/tmp}}/{prefix}XXXXXXXXXX"` || exit 1
+printf '%s\n' '-----BEGIN PUBLIC KEY-----' {key} '-----END PUBLIC KEY-----' > "$v/key"
+printf %s {sig} | openssl base64 -d -A > "$v/sig" && tail -c +{start} "$0" > "$v/payload" && \
+    openssl pkeyutl -verify -pubin -inkey "$v/key" -rawin -in "$v/payload" -sigfile "$v/sig" >/dev/null 2>&1 || \
+    {{ rm -rf "$v"; echo "$0: signature verification failed, refusing to run" >&2; exit 1; }}
+"#,
+                prefix = TEMP_PREFIX, key = signature.public_key, sig = signature.value,
+                start = size + 1),
+            None => String::new(),
+        };
+
+        format!(
+            r#"#!/bin/sh
+# compressed by zexe ({})
+# This script is exactly {} bytes long
+{}{}{}{}{}"#,
+            if stored { "stored" } else { "Zopfli" }, size, notes, find_gzip, need_openssl, verify, extract
+        )
+    })
+}
+
+// The script embeds its own length, so `render` is given a size one block
+// at a time until the text fits; the rest is padded with a comment
+fn fit_header(render: impl Fn(usize) -> String) -> io::Result<Vec<u8>> {
+    let mut size = HEADER_SIZE;
+    let header = loop {
+        let header = render(size);
+        if header.len() < size {
+            break header;
+        }
+        // One more block than the header fills, so there is room for the
+        // final newline even when it ends right on a block boundary
+        size = (header.len() / HEADER_SIZE + 1) * HEADER_SIZE;
+    };
+
+    // Pad header to exactly `size` bytes
+    let mut header_bytes = header.into_bytes();
+    header_bytes.resize(size, b'#');
+    header_bytes[size - 1] = b'\n';
+    Ok(header_bytes)
+}
+
+// Hands `produce` a writer for the payload, appended to `file` right after
+// the header, either directly or through `openssl enc` when encrypting.
+fn write_payload<F>(file: &fs::File, config: &Config, produce: F) -> io::Result<()>
+where
+    F: FnOnce(&mut dyn Write) -> io::Result<()>,
+{
+    if config.encrypt {
+        let mut child = openssl_command(false, config.password_file.as_deref())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::from(file.try_clone()?))
+            .spawn()
+            .map_err(openssl_error)?;
+        let mut stdin = child.stdin.take().unwrap();
+        let result = produce(&mut stdin);
+        drop(stdin);
+        let status = child.wait()?;
+        result?;
+        if !status.success() {
+            return Err(io::Error::other("encryption failed"));
+        }
+    } else {
+        let mut writer = BufWriter::new(file);
+        produce(&mut writer)?;
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+fn openssl_command(decrypt: bool, password_file: Option<&Path>) -> Command {
+    let mut cmd = Command::new("openssl");
+    cmd.args(["enc", if decrypt { "-d" } else { "-e" }])
+        .arg(format!("-{}", CIPHER))
+        .args(["-pbkdf2", "-iter"])
+        .arg(PBKDF2_ITERATIONS.to_string());
+    if let Some(file) = password_file {
+        let mut pass = std::ffi::OsString::from("file:");
+        pass.push(file);
+        cmd.arg("-pass").arg(pass);
+    } else if env::var_os(PASS_ENV).is_some() {
+        cmd.arg("-pass").arg(format!("env:{}", PASS_ENV));
+    }
+    cmd
+}
+
+fn openssl_error(e: io::Error) -> io::Error {
+    if e.kind() == io::ErrorKind::NotFound {
+        io::Error::new(io::ErrorKind::NotFound, "openssl not found (required for encryption)")
+    } else {
+        e
+    }
+}
+
+fn compress_file(path: &Path, config: &Config) -> io::Result<Option<FileInfo>> {
+    compress_file_to(path, None, config)
+}
+
+// Packs `path` in place, or into `output` leaving `path` alone (no backup
+// is needed then)
+fn compress_file_to(path: &Path, output: Option<&Path>, config: &Config) -> io::Result<Option<FileInfo>> {
+    let target = resolve_symlink(path, config)?;
+    let path = target.as_path();
+
+    // With --force a packed file is unpacked and packed again with the
+    // options given now, rather than wrapped twice
+    let unpacked = if is_compressed(path)? {
+        if !config.force {
+            return Err(already_packed(path)?);
+        }
+        Some(unpack_copy(path, config)?)
+    } else {
+        None
+    };
+    let base = unpacked.as_ref().map_or(path, TempFile::path);
+
+    check_file(base, config)?;
+
+    // The packed script runs anywhere, the payload only on its own architecture
+    let machine = read_elf_machine(base)?;
+    if let Some(arch) = machine.filter(|&arch| arch != env::consts::ARCH) {
+        eprintln!("Warning: {} is an {} binary and will not run on this {} host",
+                  path.display(), arch, env::consts::ARCH);
+    }
+
+    // The bits are kept on the packed file and come back with -d, but the
+    // kernel ignores them on scripts and the extracted copy is unprivileged
+    #[cfg(unix)]
+    if fs::metadata(path)?.mode() & 0o6000 != 0 {
+        eprintln!("Warning: setuid/setgid bits are kept but have no effect until the file is decompressed");
+    }
+
+    // The backup (and the original with --output-dir) keeps the symbols
+    let stripped = if config.strip { Some(strip_copy(base, config)?) } else { None };
+    let source = stripped.as_ref().map_or(base, TempFile::path);
+
+    if config.dry_run {
+        let info = dry_run_compress(path, source, config)?;
+        return Ok(info.filter(|info| !below_min_ratio(info, config)));
+    }
+
+    // Get compression options
+    let level = file_level(path, config);
+    let options = level_options(level, config);
+    
+    if config.verbose {
+        let format = read_binary_format(base)?;
+        eprintln!("Format: {}{}", format.map_or("unknown (script?)", |f| f.as_str()),
+                  machine.map_or(String::new(), |arch| format!(" ({})", arch)));
+        if !config.auto {
+            eprintln!("Compression settings:");
+            eprintln!("  Iterations: {}", options.iteration_count);
+            eprintln!("  Iterations without improvement: {}", options.iterations_without_improvement);
+            eprintln!("  Max block splits: {}", options.maximum_block_splits);
+            eprintln!("  Block type: {:?}", config.block_type);
+            eprintln!("  Threads: {}", threads_used(fs::metadata(source)?.len() as usize, config.threads));
+        }
+    }
+
+    // Fail before spending time on compression
+    let dest = output.unwrap_or(path);
+    let backup = backup_path(path, config);
+    if !config.force && output.is_none() && fs::symlink_metadata(&backup).is_ok() {
+        return Err(ZexeError::BackupExists(backup).into());
+    }
+    if !config.force && output.is_some() && fs::symlink_metadata(dest).is_ok() {
+        return Err(ZexeError::OutputExists(dest.to_path_buf()).into());
+    }
+    // Replacing the file gives this name a new inode, the other names keep the old one
+    #[cfg(unix)]
+    if output.is_none() {
+        let links = fs::metadata(path)?.nlink().saturating_sub(1);
+        if links > 0 && !config.force {
+            return Err(ZexeError::HardLinked(links).into());
+        } else if links > 0 {
+            eprintln!("Warning: {} other hard link(s) to {} keep the uncompressed contents",
+                      links, path.display());
+        }
+    }
+    if let Some(dir) = output.and_then(Path::parent) {
+        fs::create_dir_all(dir)?;
+    }
+
+    let original = Original::read(path, source)?;
+    if original.xattrs.iter().any(|(name, _)| name == CAPABILITY_XATTR) && !has_setfcap() {
+        eprintln!("{}: warning: without CAP_SETFCAP (usually root), -d cannot put its file capabilities back",
+                  path.display());
+    }
+    let encoding = choose_encoding(path, source, level, config)?;
+    let stored = matches!(encoding, Encoding::Stored);
+
+    let (temp, header_bytes, final_file, original_size) = if let Some(key) = &config.sign_key {
+        // The header carries the signature, so the payload has to be
+        // complete before the header can be written
+        let (payload, mut payload_file) = create_temp_file("zexe-payload-")?;
+        let original_size = produce_payload(path, source, config, encoding, &payload_file)?;
+        let signature = sign_payload(key, payload.path())?;
+
+        let header_bytes = generate_header(config, &original, stored, Some(&signature))?;
+        let (temp, mut final_file) = create_sibling_temp_file(dest)?;
+        final_file.write_all(&header_bytes)?;
+        payload_file.seek(SeekFrom::Start(0))?;
+        io::copy(&mut payload_file, &mut final_file)?;
+        (temp, header_bytes, final_file, original_size)
+    } else {
+        // Create compressed file with header
+        let header_bytes = generate_header(config, &original, stored, None)?;
+        let (temp, mut final_file) = create_sibling_temp_file(dest)?;
+        final_file.write_all(&header_bytes)?;
+        let original_size = produce_payload(path, source, config, encoding, &final_file)?;
+        (temp, header_bytes, final_file, original_size)
+    };
+
+    let compressed_size = final_file.metadata()?.len() - header_bytes.len() as u64;
+
+    let info = FileInfo {
+        path: path.to_path_buf(),
+        original_size,
+        compressed_size: compressed_size + header_bytes.len() as u64,
+    };
+    if below_min_ratio(&info, config) {
+        return Ok(None);
+    }
+
+    // Re-packing keeps the backup of the original; without one, the
+    // unpacked original becomes the backup rather than the old packed file
+    match &unpacked {
+        _ if output.is_some() => {}
+        Some(_) if fs::symlink_metadata(&backup).is_ok() => {}
+        Some(original) => create_backup(original.path(), &backup, false)?,
+        None => create_backup(path, &backup, config.force)?,
+    }
+
+    // Copy permissions
+    #[allow(unused_mut)]
+    let mut permissions = fs::metadata(path)?.permissions();
+    #[cfg(unix)]
+    if config.data {
+        permissions.set_mode(permissions.mode() | 0o100);
+    }
+    fs::set_permissions(temp.path(), permissions)?;
+    if !config.no_xattr {
+        let xattrs: Xattrs = original.xattrs.iter().filter(|(name, _)| name != CAPABILITY_XATTR).cloned().collect();
+        restore_xattrs(path, temp.path(), &xattrs);
+    }
+
+    // Replace original
+    replace_file(temp, dest, config)?;
+
+    if let Some(key) = &config.gpg_key {
+        gpg_sign(dest, key)?;
+    }
+
+    if config.verbose {
+        eprintln!("Compression complete:");
+        eprintln!("  Original size: {} bytes", original_size);
+        eprintln!("  Compressed size: {} bytes", compressed_size + header_bytes.len() as u64);
+        eprintln!("  Header size: {} bytes", header_bytes.len());
+        // Payload only, so it can go negative on small files but not divide by zero
+        if original_size > 0 {
+            eprintln!("  Compression ratio: {:.1}%",
+                     (original_size as f64 - compressed_size as f64) * 100.0 / original_size as f64);
+        }
+    }
+
+    Ok(Some(info))
+}
+
+// Packs `files` as one tar into `out`, which extracts them all and runs
+// the member named like argv[0] (so symlinks to the bundle dispatch), or
+// else the entry point
+fn bundle_files(out: &Path, files: &[PathBuf], config: &Config) -> io::Result<FileInfo> {
+    let mut members = Vec::with_capacity(files.len());
+    for file in files {
+        if file.is_dir() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                format!("{}: {}", file.display(), ZexeError::IsDirectory)));
+        }
+        check_file(file, config).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", file.display(), e)))?;
+        let name = file.file_name().and_then(|n| n.to_str())
+            .filter(|n| n.len() <= 100 && !n.contains('\n'))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput,
+                format!("{}: bundled names must be UTF-8, on one line and at most 100 bytes",
+                        file.display())))?;
+        if members.iter().any(|(other, _)| other == name) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                format!("two files named {} (bundled files are extracted side by side)", name)));
+        }
+        members.push((name.to_string(), file.as_path()));
+    }
+    let entry = match &config.entry {
+        Some(entry) if !members.iter().any(|(name, _)| name == entry) => {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                format!("--entry {} is not one of the bundled files", entry)));
+        }
+        Some(entry) => entry.clone(),
+        None => members.first().map(|(name, _)| name.clone())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "No files to bundle"))?,
+    };
+    if !config.force && !config.dry_run && fs::symlink_metadata(out).is_ok() {
+        return Err(ZexeError::OutputExists(out.to_path_buf()).into());
+    }
+
+    let tar = write_tar(&members)?;
+    report(config, format_args!("Compressing {} files into {} with Zopfli ({} level, this may take a while)...",
+                                members.len(), out.display(), config.compression_level.as_str()));
+    let options = level_options(config.compression_level, config);
+    let compressed = if config.threads > 1 {
+        compress_zopfli_parallel(&tar, options, config.block_type, parallel_chunk_size(tar.len(), config.threads))?
+    } else {
+        compress_zopfli(&tar, options, config.block_type)?
+    };
+    let header = generate_bundle_header(&entry, posix_cksum(&tar[..])?)?;
+    let info = FileInfo {
+        path: out.to_path_buf(),
+        original_size: members.iter().map(|(_, path)| fs::metadata(path).map_or(0, |m| m.len())).sum(),
+        compressed_size: (header.len() + compressed.len()) as u64,
+    };
+    if config.dry_run {
+        return Ok(info);
+    }
+
+    let (temp, mut file) = create_sibling_temp_file(out)?;
+    file.write_all(&header)?;
+    file.write_all(&compressed)?;
+    #[cfg(unix)]
+    fs::set_permissions(temp.path(), fs::Permissions::from_mode(0o755))?;
+    replace_file(temp, out, config)?;
+    Ok(info)
+}
+
+fn generate_bundle_header(entry: &str, cksum: Cksum) -> io::Result<Vec<u8>> {
+    fit_header(|size| format!(
+        r#"#!/bin/sh
+# compressed by zexe (Zopfli)
+# This script is exactly {size} bytes long
+{cksum_prefix}{cksum}
+{bundle_prefix}{entry}
+{find_gzip}command -v tar >/dev/null 2>&1 || {{ echo "$0: error: 'tar' not found; install tar to run this program" >&2; exit 1; }}
+d="${{TMPDIR:-/tmp}}"
+find "$d/." ! -name . -prune -type d -name '{prefix}??????????' -mtime +{age} -exec rm -rf {{}} + 2>/dev/null
+tmp=`mktemp -d "$d/{prefix}XXXXXXXXXX"` || exit 1
+trap 'rm -rf "$tmp"' 0
+tail -c +{start} "$0" | $z -dc 2>/dev/null | (cd "$tmp" && tar xf -) && {{
+    p="$tmp/"`basename "$0"`; [ -f "$p" ] || p="$tmp/"{quoted}
+    (sleep 5; rm -rf "$tmp") >/dev/null 2>&1 </dev/null &
+    exec "$p" "$@"; }}
+exit $?
+"#,
+        size = size, cksum_prefix = String::from_utf8_lossy(CKSUM_PREFIX), cksum = cksum,
+        bundle_prefix = String::from_utf8_lossy(BUNDLE_PREFIX), entry = entry, find_gzip = FIND_GZIP,
+        prefix = TEMP_PREFIX, age = STALE_TEMP_AGE.as_secs() / 86400 - 1, start = size + 1,
+        quoted = shell_quote(entry)))
+}
+
+// A ustar archive of plain files, the subset every tar extracts
+fn write_tar(members: &[(String, &Path)]) -> io::Result<Vec<u8>> {
+    let mut tar = Vec::new();
+    for (name, path) in members {
+        let data = fs::read(path)?;
+        let metadata = fs::metadata(path)?;
+        #[cfg(unix)]
+        let mode = metadata.mode() & 0o7777;
+        #[cfg(not(unix))]
+        let mode = 0o755;
+        let mtime = metadata.modified()?.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+
+        let mut block = [0u8; 512];
+        block[..name.len()].copy_from_slice(name.as_bytes());
+        let mut field = |at: usize, len: usize, value: u64| {
+            let text = format!("{:0width$o}", value, width = len - 1);
+            block[at..at + len - 1].copy_from_slice(text.as_bytes());
+        };
+        field(100, 8, mode as u64);
+        field(108, 8, 0);
+        field(116, 8, 0);
+        field(124, 12, data.len() as u64);
+        field(136, 12, mtime);
+        block[156] = b'0';
+        block[257..265].copy_from_slice(b"ustar\x0000");
+        // The checksum is computed with its own field set to spaces
+        block[148..156].fill(b' ');
+        let sum: u32 = block.iter().map(|&b| b as u32).sum();
+        block[148..155].copy_from_slice(format!("{:06o}\0", sum).as_bytes());
+
+        tar.extend_from_slice(&block);
+        tar.extend_from_slice(&data);
+        tar.resize(tar.len().div_ceil(512) * 512, 0);
+    }
+    tar.resize(tar.len() + 1024, 0);
+    Ok(tar)
+}
+
+// Reads back what write_tar produces: (name, mode, contents) per member.
+// Names are checked since -d writes them next to the bundle.
+fn read_tar(tar: &[u8]) -> io::Result<Vec<(String, u32, &[u8])>> {
+    let damaged = || io::Error::new(io::ErrorKind::InvalidData, "damaged bundle");
+    let octal = |field: &[u8]| {
+        let text = std::str::from_utf8(field).ok()?;
+        u64::from_str_radix(text.trim_matches(|c| c == '\0' || c == ' '), 8).ok()
+    };
+    let mut members = Vec::new();
+    let mut at = 0;
+    while let Some(block) = tar.get(at..at + 512) {
+        if block.iter().all(|&b| b == 0) {
+            return Ok(members);
+        }
+        let name = block[..100].split(|&b| b == 0).next().and_then(|n| std::str::from_utf8(n).ok())
+            .filter(|n| !n.is_empty() && *n != "." && *n != ".." && !n.contains('/'))
+            .ok_or_else(damaged)?;
+        if !matches!(block[156], b'0' | 0) {
+            return Err(damaged());
+        }
+        let mode = octal(&block[100..108]).ok_or_else(damaged)? as u32 & 0o7777;
+        let size = octal(&block[124..136]).ok_or_else(damaged)? as usize;
+        let data = tar.get(at + 512..at + 512 + size).ok_or_else(damaged)?;
+        members.push((name.to_string(), mode, data));
+        at += 512 + size.div_ceil(512) * 512;
+    }
+    Err(damaged())
+}
+
+// Smallest payload the header's format allows: an openssl salt header plus
+// one cipher block, or a gzip header, an empty deflate block and trailer
+fn min_payload_size(header: &[u8]) -> u64 {
+    if header_has(header, ENCRYPTED_MARKER) {
+        32
+    } else if header_has(header, STORED_MARKER) {
+        0
+    } else {
+        20
+    }
+}
+
+// A plain foo.gz, as gzip writes it, unpacks to foo like with `gzip -dk`
+fn plain_gzip_output(path: &Path) -> io::Result<Option<PathBuf>> {
+    let Some(stem) = path.to_str().and_then(|p| p.strip_suffix(".gz")).filter(|s| !s.ends_with('/')) else {
+        return Ok(None);
+    };
+    let mut magic = [0u8; 2];
+    let mut file = fs::File::open(path)?;
+    if file.read(&mut magic)? < 2 || magic != [0x1f, 0x8b] {
+        return Ok(None);
+    }
+    Ok(Some(PathBuf::from(stem)))
+}
+
+// The .gz file is kept, as nothing in it says the output replaces it
+fn decompress_plain_gzip(path: &Path, output: &Path, config: &Config) -> io::Result<Option<FileInfo>> {
+    if !config.force && !config.dry_run && fs::symlink_metadata(output).is_ok() {
+        return Err(ZexeError::OutputExists(output.to_path_buf()).into());
+    }
+    let input = fs::File::open(path)?;
+    let compressed_size = input.metadata()?.len();
+    if config.dry_run {
+        return Ok(Some(FileInfo {
+            path: path.to_path_buf(),
+            original_size: decode_payload(input, &[], config, &mut CountingSink(0))?,
+            compressed_size,
+        }));
+    }
+
+    let (temp, file) = create_sibling_temp_file(output)?;
+    let mut writer = BufWriter::new(file);
+    let original_size = decode_payload(input, &[], config, &mut writer)?;
+    writer.flush()?;
+    fs::set_permissions(temp.path(), fs::metadata(path)?.permissions())?;
+    replace_file(temp, output, config)?;
+
+    Ok(Some(FileInfo {
+        path: output.to_path_buf(),
+        original_size,
+        compressed_size,
+    }))
+}
+
+// -d on a bundle writes its files next to it and leaves the bundle alone
+fn unbundle_file(path: &Path, input: fs::File, header: &[u8], config: &Config) -> io::Result<Option<FileInfo>> {
+    let compressed_size = input.metadata()?.len();
+    let mut tar = Vec::new();
+    decode_payload(input, header, config, &mut tar)?;
+    let members = read_tar(&tar)?;
+
+    let dir = path.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    if !config.force {
+        if let Some((name, _, _)) = members.iter().find(|(name, _, _)| fs::symlink_metadata(dir.join(name)).is_ok()) {
+            return Err(ZexeError::OutputExists(dir.join(name)).into());
+        }
+    }
+    let mut original_size = 0;
+    for (name, mode, data) in &members {
+        let dest = dir.join(name);
+        original_size += data.len() as u64;
+        if config.dry_run {
+            continue;
+        }
+        let (temp, mut file) = create_sibling_temp_file(&dest)?;
+        file.write_all(data)?;
+        #[cfg(unix)]
+        fs::set_permissions(temp.path(), fs::Permissions::from_mode(*mode))?;
+        replace_file(temp, &dest, config)?;
+        report(config, format_args!("  {}", dest.display()));
+    }
+
+    Ok(Some(FileInfo {
+        path: path.to_path_buf(),
+        original_size,
+        compressed_size,
+    }))
+}
+
+// The refusal for a packed `path`, saying how it was packed
+fn already_packed(path: &Path) -> io::Result<io::Error> {
+    let header = read_header(&mut fs::File::open(path)?)?;
+    let mut packing = if header_has(&header, BUNDLE_PREFIX) {
+        "bundle".to_string()
+    } else if header_has(&header, STORED_MARKER) {
+        "stored".to_string()
+    } else {
+        "Zopfli".to_string()
+    };
+    if header_has(&header, ENCRYPTED_MARKER) {
+        packing += ", encrypted";
+    }
+    Ok(ZexeError::AlreadyCompressed(packing).into())
+}
+
+// The program inside the packed file `path`, in a temporary file with the
+// same permissions
+fn unpack_copy(path: &Path, config: &Config) -> io::Result<TempFile> {
+    let mut input = fs::File::open(path)?;
+    let header = read_header(&mut input)?;
+    if header_has(&header, BUNDLE_PREFIX) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+            "is a bundle; unpack it with -d and bundle the files again"));
+    }
+    let (temp, file) = create_temp_file("zexe-unpacked-")?;
+    let mut writer = BufWriter::new(file);
+    decode_payload(input, &header, config, &mut writer)?;
+    writer.flush()?;
+    drop(writer);
+    fs::set_permissions(temp.path(), fs::metadata(path)?.permissions())?;
+    Ok(temp)
+}
+
+// Copies `path` to a temporary file and strips its symbols with the
+// system `strip`, which knows the binary formats (no ELF rewriting here)
+fn strip_copy(path: &Path, config: &Config) -> io::Result<TempFile> {
+    match read_binary_format(path)? {
+        Some(BinaryFormat::Elf | BinaryFormat::MachO | BinaryFormat::MachOFat) => {}
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidInput,
+            "--strip only applies to ELF and Mach-O binaries")),
+    }
+
+    let (temp, mut file) = create_temp_file("zexe-strip-")?;
+    io::copy(&mut fs::File::open(path)?, &mut file)?;
+    drop(file);
+
+    let output = Command::new("strip").arg(temp.path()).stdin(Stdio::null()).output()
+        .map_err(|e| if e.kind() == io::ErrorKind::NotFound {
+            io::Error::new(io::ErrorKind::NotFound, "strip not found (required for --strip)")
+        } else {
+            e
+        })?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!("strip failed: {}",
+            String::from_utf8_lossy(&output.stderr).lines().next().unwrap_or("unknown error"))));
+    }
+
+    report(config, format_args!("Stripped {}: {} -> {} bytes", path.display(),
+                                fs::metadata(path)?.len(), fs::metadata(temp.path())?.len()));
+    Ok(temp)
+}
+
+// Reports and returns true when packing would not save at least
+// --min-ratio percent, wrapper included
+fn below_min_ratio(info: &FileInfo, config: &Config) -> bool {
+    match config.min_ratio {
+        Some(min) if info.compression_ratio() < min => {
+            report(config, format_args!("{}: skipped, {:.1}% compression is below --min-ratio {}%{}",
+                                        info.path.display(), info.compression_ratio(), min,
+                                        if config.dry_run { " [dry run]" } else { "" }));
+            true
+        }
+        _ => false,
+    }
+}
+
+// How the original is laid out after the header. --auto compresses before
+// the header is written, since storing wins when nothing is saved.
+enum Encoding {
+    Zopfli(CompressionLevel),
+    Auto { original_size: u64, compressed: Vec<u8> },
+    Stored,
+}
+
+// `source` is read, `path` (what the user named) is reported
+fn choose_encoding(path: &Path, source: &Path, level: CompressionLevel, config: &Config) -> io::Result<Encoding> {
+    if config.store {
+        return Ok(Encoding::Stored);
+    }
+    if !config.auto {
+        return Ok(Encoding::Zopfli(level));
+    }
+
+    report(config, format_args!("Compressing {} with Zopfli (trying every level, this may take a while)...",
+                                path.display()));
+    let data = FileData::read(source)?;
+    let (_, compressed) = compress_auto(&data, config)?;
+    if compressed.len() < data.len() {
+        Ok(Encoding::Auto { original_size: data.len() as u64, compressed })
+    } else {
+        report(config, format_args!("  no level saves anything, storing {} as is", path.display()));
+        Ok(Encoding::Stored)
+    }
+}
+
+// Compresses `source` and appends the (possibly encrypted) payload to `file`,
+// reporting it as `path`. Returns the size of the original.
+fn produce_payload(path: &Path, source: &Path, config: &Config, encoding: Encoding,
+                   file: &fs::File) -> io::Result<u64> {
+    match encoding {
+        Encoding::Auto { original_size, compressed } => {
+            write_payload(file, config, |w| w.write_all(&compressed))?;
+            Ok(original_size)
+        }
+        Encoding::Stored => {
+            report(config, format_args!("Storing {} without compression...", path.display()));
+
+            let mut input = fs::File::open(source)?;
+            let mut read = 0;
+            write_payload(file, config, |w| {
+                read = io::copy(&mut input, w)?;
+                Ok(())
+            })?;
+            Ok(read)
+        }
+        Encoding::Zopfli(level) => {
+            report(config, format_args!("Compressing {} with Zopfli ({} level, this may take a while)...",
+                                        path.display(), level.as_str()));
+            let options = level_options(level, config);
+            if config.threads > 1 {
+                let data = FileData::read(source)?;
+                let compressed = compress_zopfli_parallel(&data, options, config.block_type,
+                                                          parallel_chunk_size(data.len(), config.threads))?;
+                write_payload(file, config, |w| w.write_all(&compressed))?;
+                return Ok(data.len() as u64);
+            }
+
+            // Stream the original through the encoder so that neither side is
+            // held entirely in memory
+            let input = BufReader::new(fs::File::open(source)?);
+            let mut read = 0;
+            write_payload(file, config, |w| {
+                read = compress_zopfli_stream(input, w, options, config.block_type)?;
+                Ok(())
+            })?;
+            Ok(read)
+        }
+    }
+}
+
+// Computes what compress_file would produce without touching the disk:
+// the payload (read from `source`, see Original::read) is compressed into a
+// counting sink and discarded.
+fn dry_run_compress(path: &Path, source: &Path, config: &Config) -> io::Result<Option<FileInfo>> {
+    // A signature has a fixed size, so a placeholder gives the exact header
+    let placeholder = config.sign_key.as_ref().map(|_| Signature::placeholder());
+    let encoding = choose_encoding(path, source, file_level(path, config), config)?;
+    let stored = matches!(encoding, Encoding::Stored);
+    let header_size = generate_header(config, &Original::read(path, source)?, stored,
+                                      placeholder.as_ref())?.len() as u64;
+
+    let (original_size, mut payload_size) = match encoding {
+        Encoding::Auto { original_size, compressed } => (original_size, compressed.len() as u64),
+        Encoding::Stored => {
+            let size = fs::metadata(source)?.len();
+            (size, size)
+        }
+        Encoding::Zopfli(level) if config.threads > 1 => {
+            let data = FileData::read(source)?;
+            let compressed = compress_zopfli_parallel(&data, level_options(level, config), config.block_type,
+                                                      parallel_chunk_size(data.len(), config.threads))?;
+            (data.len() as u64, compressed.len() as u64)
+        }
+        Encoding::Zopfli(level) => {
+            let input = BufReader::new(fs::File::open(source)?);
+            let mut sink = CountingSink(0);
+            let read = compress_zopfli_stream(input, &mut sink, level_options(level, config),
+                                              config.block_type)?;
+            (read, sink.0)
+        }
+    };
+
+    if config.encrypt {
+        payload_size = encrypted_size(payload_size);
+    }
+
+    Ok(Some(FileInfo {
+        path: path.to_path_buf(),
+        original_size,
+        compressed_size: header_size + payload_size,
+    }))
+}
+
+struct CountingSink(u64);
+
+impl Write for CountingSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Size of `openssl enc` output: "Salted__" + 8-byte salt, then the CBC
+// ciphertext with PKCS#7 padding (always at least one byte of padding).
+fn encrypted_size(plain: u64) -> u64 {
+    16 + (plain / 16 + 1) * 16
+}
+
+fn check_payload_size(header: &[u8], compressed_size: u64) -> io::Result<()> {
+    // Only a stored empty file has nothing after the header
+    if compressed_size <= header.len() as u64 && !header_has(header, STORED_MARKER) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+            "corrupted compressed file"));
+    }
+    let payload_size = compressed_size - header.len() as u64;
+    if payload_size > 0 && payload_size < min_payload_size(header) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+            format!("payload is only {} bytes, too short to be valid (truncated file?)", payload_size)));
+    }
+    Ok(())
+}
+
+// Unpacks `path` without writing it anywhere, like gzip -t: the gzip stream
+// is checked on the way and the result against the cksum of the original,
+// when recorded
+fn test_integrity(path: &Path, config: &Config) -> io::Result<Option<FileInfo>> {
+    if !is_compressed(path)? {
+        return Err(ZexeError::NotCompressed.into());
+    }
+
+    let mut input = fs::File::open(path)?;
+    let compressed_size = input.metadata()?.len();
+    let header = read_header(&mut input)?;
+    check_payload_size(&header, compressed_size)?;
+    let mut unpacked = CksumWriter::new();
+    let decoded = decode_payload(input, &header, config, &mut unpacked);
+    let cksum = unpacked.finish();
+    let recorded = parse_header_cksum(&header);
+    let checked = decoded.and_then(|_| match recorded {
+        Some(recorded) if cksum != recorded => Err(io::Error::new(io::ErrorKind::InvalidData,
+            format!("cksum mismatch: unpacks to {} {}, recorded {} {}",
+                    cksum.crc, cksum.size, recorded.crc, recorded.size))),
+        _ => Ok(()),
+    });
+    if let Err(e) = checked {
+        // The counterpart of the OK line; the reason follows with the error
+        if e.kind() == io::ErrorKind::InvalidData {
+            println!("{}: CORRUPT", path.display());
+        }
+        return Err(e);
+    }
+
+    match recorded {
+        Some(recorded) => println!("{}: OK ({} bytes, cksum {})", path.display(), recorded.size, recorded.crc),
+        // Older files: the gzip trailer was all there was to check
+        None => println!("{}: OK ({} bytes, no cksum recorded)", path.display(), cksum.size),
+    }
+    Ok(None)
+}
+
+fn decompress_file(path: &Path, config: &Config) -> io::Result<Option<FileInfo>> {
+    let target = resolve_symlink(path, config)?;
+    let path = target.as_path();
+
+    if !is_compressed(path)? {
+        return match plain_gzip_output(path)? {
+            Some(output) => decompress_plain_gzip(path, &output, config),
+            None => Err(ZexeError::NotCompressed.into()),
+        };
+    }
+
+    let mut input = fs::File::open(path)?;
+    let compressed_size = input.metadata()?.len();
+
+    let header = read_header(&mut input)?;
+    check_payload_size(&header, compressed_size)?;
+    if header_has(&header, BUNDLE_PREFIX) {
+        return unbundle_file(path, input, &header, config);
+    }
+
+    // Decode the whole payload but keep nothing, so corruption or a wrong
+    // passphrase still shows up
+    if config.dry_run {
+        return Ok(Some(FileInfo {
+            path: path.to_path_buf(),
+            original_size: decode_payload(input, &header, config, &mut CountingSink(0))?,
+            compressed_size,
+        }));
+    }
+
+    // Save
+    let (temp, output) = create_sibling_temp_file(path)?;
+    let original_size = if config.no_sparse {
+        let mut output = BufWriter::new(output);
+        let size = decode_payload(input, &header, config, &mut output)?;
+        output.flush()?;
+        size
+    } else {
+        let mut output = SparseWriter::new(output);
+        let size = decode_payload(input, &header, config, &mut output)?;
+        output.finish()?;
+        size
+    };
+
+    #[allow(unused_mut)]
+    let mut permissions = fs::metadata(path)?.permissions();
+    #[cfg(unix)]
+    if let Some(mode) = header_value(&header, MODE_PREFIX).and_then(|m| u32::from_str_radix(m.trim(), 8).ok()) {
+        permissions.set_mode(mode);
+    }
+    fs::set_permissions(temp.path(), permissions)?;
+    let (caps, xattrs): (Xattrs, Xattrs) = parse_header_xattrs(&header).into_iter()
+        .partition(|(name, _)| name == CAPABILITY_XATTR);
+    if !config.no_xattr {
+        restore_xattrs(path, temp.path(), &xattrs);
+    }
+
+    replace_file(temp, path, config)?;
+
+    // Set last, on the file in place, so no later write or copy drops them
+    if !config.no_xattr || config.preserve_caps {
+        restore_xattrs(path, path, &caps);
+    }
+
+    Ok(Some(FileInfo {
+        path: path.to_path_buf(),
+        original_size,
+        compressed_size,
+    }))
+}
+
+// Writes a file block by block, seeking over blocks that are all zeros so
+// they become holes, as the original may have been sparse
+struct SparseWriter {
+    file: fs::File,
+    block: Vec<u8>,
+    len: u64,
+}
+
+impl SparseWriter {
+    const BLOCK_SIZE: usize = 4096;
+
+    fn new(file: fs::File) -> Self {
+        SparseWriter { file, block: Vec::with_capacity(Self::BLOCK_SIZE), len: 0 }
+    }
+
+    fn flush_block(&mut self) -> io::Result<()> {
+        if self.block.iter().all(|&b| b == 0) {
+            self.file.seek(SeekFrom::Current(self.block.len() as i64))?;
+        } else {
+            self.file.write_all(&self.block)?;
+        }
+        self.len += self.block.len() as u64;
+        self.block.clear();
+        Ok(())
+    }
+
+    // A trailing hole has nothing written after it, so the length is set
+    // explicitly
+    fn finish(mut self) -> io::Result<fs::File> {
+        self.flush_block()?;
+        self.file.set_len(self.len)?;
+        Ok(self.file)
+    }
+}
+
+impl Write for SparseWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = buf.len().min(Self::BLOCK_SIZE - self.block.len());
+        self.block.extend_from_slice(&buf[..n]);
+        if self.block.len() == Self::BLOCK_SIZE {
+            self.flush_block()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Held while openssl may prompt for a passphrase (see unpack_files)
+static PASSPHRASE_PROMPT: Mutex<()> = Mutex::new(());
+
+// Writes the original program from `input`, positioned right after
+// `header`, to `output` and returns its size
+fn decode_payload(input: fs::File, header: &[u8], config: &Config,
+                  output: &mut dyn Write) -> io::Result<u64> {
+    let stored = header_has(header, STORED_MARKER);
+    // flate2 reports a broken stream as bad input; here it is bad data
+    let damaged = |e: io::Error| match e.kind() {
+        io::ErrorKind::InvalidInput | io::ErrorKind::UnexpectedEof => {
+            io::Error::new(io::ErrorKind::InvalidData, format!("damaged payload: {}", e))
+        }
+        _ => e,
+    };
+    let inflate = |mut reader: Box<dyn BufRead>| -> io::Result<Box<dyn Read>> {
+        if stored {
+            return Ok(reader);
+        }
+        // Anything but gzip here is damage, not another codec to guess at
+        if !reader.fill_buf()?.starts_with(&[0x1f, 0x8b]) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                "payload is neither gzip data nor marked as stored (damaged file?)"));
+        }
+        // --threads writes one gzip member per chunk
+        Ok(Box::new(MultiGzDecoder::new(reader)))
+    };
+
+    // A payload that inflates past the recorded size is damaged or hostile;
+    // stop there rather than fill the disk
+    let recorded = parse_header_cksum(header).map(|cksum| cksum.size);
+    let limit = recorded.into_iter().chain(config.max_output).min();
+    let copy = |mut reader: Box<dyn Read>, output: &mut dyn Write| -> io::Result<u64> {
+        let Some(limit) = limit else {
+            return io::copy(&mut reader, output).map_err(damaged);
+        };
+        let copied = io::copy(&mut reader.take(limit.saturating_add(1)), output).map_err(damaged)?;
+        if copied > limit {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                format!("payload expands past {} bytes (damaged file or decompression bomb?)", limit)));
+        }
+        Ok(copied)
+    };
+
+    // Decompress from the end of the header (using flate2 for decompression)
+    if header_has(header, ENCRYPTED_MARKER) {
+        // Files unpacked in parallel take turns at the passphrase prompt
+        let _prompt = (config.password_file.is_none() && env::var_os(PASS_ENV).is_none())
+            .then(|| PASSPHRASE_PROMPT.lock().unwrap_or_else(|e| e.into_inner()));
+        let mut child = openssl_command(true, config.password_file.as_deref())
+            .stdin(Stdio::from(input))
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(openssl_error)?;
+        let stdout = child.stdout.take().unwrap();
+        let copied = inflate(Box::new(BufReader::new(stdout)))
+            .and_then(|reader| copy(reader, output));
+        if !child.wait()?.success() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                "decryption failed (wrong passphrase?)"));
+        }
+        copied
+    } else {
+        copy(inflate(Box::new(BufReader::new(input)))?, output)
+    }
+}
+
+fn info_file(path: &Path) -> io::Result<Option<FileInfo>> {
+    if !is_compressed(path)? {
+        return Err(ZexeError::NotCompressed.into());
+    }
+
+    let mut input = fs::File::open(path)?;
+    let compressed_size = input.metadata()?.len();
+    let header = read_header(&mut input)?;
+    let payload_size = compressed_size.saturating_sub(header.len() as u64);
+    let encrypted = header_has(&header, ENCRYPTED_MARKER);
+    let stored = header_has(&header, STORED_MARKER);
+
+    println!("{}: compressed by zexe", path.display());
+    println!("  Header size: {} bytes", header.len());
+    println!("  Payload offset: {}", header.len());
+    println!("  Payload size: {} bytes", payload_size);
+    println!("  Extraction: {}",
+             if header_has(&header, EXTRACT_ONLY_MARKER) {
+                 "to a file, without running"
+             } else if header_has(&header, CACHED_MARKER) {
+                 "user cache"
+             } else {
+                 "temporary directory"
+             });
+    if let Some(name) = header_value(&header, NAME_PREFIX) {
+        println!("  Original name: {}", name);
+    }
+    if let Some(entry) = header_value(&header, BUNDLE_PREFIX) {
+        println!("  Bundle entry point: {}", entry);
+    }
+    if let Some(args) = header_value(&header, ARGS_PREFIX) {
+        println!("  Default arguments: {}", args);
+    }
+    if let Some(mode) = header_value(&header, MODE_PREFIX) {
+        println!("  Original mode: {} (data file)", mode);
+    }
+    if let Some(signature) = Signature::from_header(&header) {
+        println!("  Signed by: {}", signature.public_key);
+    }
+    let xattrs = parse_header_xattrs(&header);
+    if !xattrs.is_empty() {
+        let names: Vec<&str> = xattrs.iter().map(|(name, _)| name.as_str()).collect();
+        println!("  Extended attributes: {}", names.join(", "));
+    }
+    println!("  Compression: {}", if stored { "none (stored)" } else { "gzip (Zopfli)" });
+    println!("  Encrypted: {}",
+             if encrypted { header_value(&header, ENCRYPTED_MARKER).map_or("yes", str::trim) } else { "no" });
+
+    if let Some(cksum) = parse_header_cksum(&header) {
+        println!("  Original size: {} bytes", cksum.size);
+        println!("  Original cksum: {}", cksum.crc);
+    } else if !encrypted && !stored && payload_size >= 4 {
+        // Older files: fall back to the gzip trailer (size modulo 4 GiB)
+        let mut isize = [0u8; 4];
+        input.seek(SeekFrom::End(-4))?;
+        input.read_exact(&mut isize)?;
+        println!("  Original size: {} bytes (from gzip trailer)", u32::from_le_bytes(isize));
+    }
+
+    Ok(None)
+}
+
+// Removes directories created by the generated scripts (zexe.XXXXXXXXXX)
+// that are older than `max_age`. Anything else in `dir` is left alone,
+// including symlinks matching the pattern.
+fn gc_temp_dirs(dir: &Path, max_age: Duration, verbose: bool) -> io::Result<usize> {
+    let mut removed = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let Some(suffix) = name.to_str().and_then(|n| n.strip_prefix(TEMP_PREFIX)) else {
+            continue;
+        };
+        if suffix.len() != 10 || !suffix.bytes().all(|b| b.is_ascii_alphanumeric()) {
+            continue;
+        }
+
+        let metadata = entry.path().symlink_metadata()?;
+        if !metadata.is_dir() {
+            continue;
+        }
+        let age = metadata.modified()?.elapsed().unwrap_or_default();
+        if age < max_age {
+            continue;
+        }
+
+        match fs::remove_dir_all(entry.path()) {
+            Ok(()) => {
+                if verbose {
+                    eprintln!("Removed {}", entry.path().display());
+                }
+                removed += 1;
+            }
+            // Directories of other users are not ours to clean
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(removed)
+}
+
+fn benchmark_file(path: &Path, config: &Config) -> io::Result<Option<FileInfo>> {
+    if is_compressed(path)? {
+        return Err(already_packed(path)?);
+    }
+
+    let data = FileData::read(path)?;
+    let mut levels = PRESET_LEVELS.to_vec();
+    if let CompressionLevel::Custom = config.compression_level {
+        levels.push(CompressionLevel::Custom);
+    }
+
+    // Storing is the baseline every level has to beat
+    let mut rows = vec![("stored", data.len() as u64 + HEADER_SIZE as u64, Duration::ZERO, Duration::ZERO)];
+    for level in levels {
+        let options = level_options(level, config);
+
+        let start = Instant::now();
+        let compressed = compress_zopfli(&data, options, config.block_type)?;
+        let compress_time = start.elapsed();
+
+        let start = Instant::now();
+        let mut decompressed = Vec::with_capacity(data.len());
+        GzDecoder::new(&compressed[..]).read_to_end(&mut decompressed)?;
+        let decompress_time = start.elapsed();
+
+        if decompressed[..] != data[..] {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                format!("round-trip mismatch at level {}", level.as_str())));
+        }
+
+        rows.push((level.as_str(), (compressed.len() + HEADER_SIZE) as u64, compress_time, decompress_time));
+    }
+
+    // Smallest first; the sort is stable, so ties keep the faster level
+    rows.sort_by_key(|&(_, size, _, _)| size);
+    println!("{}: {} bytes", path.display(), data.len());
+    println!("  {:<4} {:<8} {:>12} {:>8} {:>12} {:>12}",
+             "Rank", "Level", "Size", "Ratio", "Compress", "Decompress");
+    for (rank, (name, size, compress_time, decompress_time)) in rows.into_iter().enumerate() {
+        let info = FileInfo {
+            path: path.to_path_buf(),
+            original_size: data.len() as u64,
+            compressed_size: size,
+        };
+        println!("  {:<4} {:<8} {:>12} {:>7.1}% {:>12} {:>12}",
+                 rank + 1, name, info.compressed_size, info.compression_ratio(),
+                 format_duration(compress_time), format_duration(decompress_time));
+    }
+
+    Ok(None)
+}
+
+// Compresses `data` with every preset level in parallel and keeps the
+// smallest result.
+fn compress_auto(data: &[u8], config: &Config) -> io::Result<(CompressionLevel, Vec<u8>)> {
+    let results: Vec<io::Result<Vec<u8>>> = thread::scope(|scope| {
+        let handles: Vec<_> = PRESET_LEVELS.iter()
+            .map(|&level| scope.spawn(move || {
+                compress_zopfli(data, level_options(level, config), config.block_type)
+            }))
+            .collect();
+        handles.into_iter()
+            .map(|h| h.join().unwrap_or_else(|_| Err(io::Error::other("compression thread panicked"))))
+            .collect()
+    });
+
+    let mut sizes = Vec::with_capacity(PRESET_LEVELS.len());
+    let mut best: Option<(CompressionLevel, Vec<u8>)> = None;
+    for (level, result) in PRESET_LEVELS.into_iter().zip(results) {
+        let compressed = result?;
+        sizes.push((level, compressed.len()));
+        // Losing buffers are dropped as soon as a smaller one is found
+        if best.as_ref().is_none_or(|(_, b)| compressed.len() < b.len()) {
+            best = Some((level, compressed));
+        }
+    }
+    let (winner, compressed) = best.unwrap();
+
+    // The header is the same whatever the level, so only payloads are compared
+    for (level, size) in sizes {
+        report(config, format_args!("  {:<8} {:>12} bytes of payload{}", level.as_str(), size,
+                                    if level == winner { "  <- selected" } else { "" }));
+    }
+
+    Ok((winner, compressed))
+}
+
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+    if bytes < 1024 {
+        return format!("{}B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1}{}", size, UNITS[unit])
+}
+
+// "4096", "64K", "64M" or "2G", in bytes (powers of 1024 like format_size)
+fn parse_size(s: &str) -> Option<u64> {
+    let (digits, shift) = match s.as_bytes().last()?.to_ascii_uppercase() {
+        b'K' => (&s[..s.len() - 1], 10),
+        b'M' => (&s[..s.len() - 1], 20),
+        b'G' => (&s[..s.len() - 1], 30),
+        _ => (s, 0),
+    };
+    digits.parse::<u64>().ok()?.checked_mul(1 << shift)
+}
+
+// Splits --args into words the way sh does, without expansions: blanks
+// separate words, single quotes keep everything, double quotes keep all
+// but \", \\, \$ and \`, and a backslash outside quotes keeps the next
+// character. None for an unterminated quote.
+fn split_args(s: &str) -> Option<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => words.extend(word.take()),
+            '\\' => word.get_or_insert_with(String::new).extend(chars.next()),
+            '\'' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next()? {
+                        '\'' => break,
+                        c => word.push(c),
+                    }
+                }
+            }
+            '"' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next()? {
+                        '"' => break,
+                        '\\' => match chars.next()? {
+                            c @ ('"' | '\\' | '$' | '`') => word.push(c),
+                            c => {
+                                word.push('\\');
+                                word.push(c);
+                            }
+                        },
+                        c => word.push(c),
+                    }
+                }
+            }
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    Some(words)
+}
+
+fn format_duration(d: Duration) -> String {
+    format!("{:.3}s", d.as_secs_f64())
+}
+
+fn compress_zopfli(data: &[u8], options: Options, block_type: BlockType) -> io::Result<Vec<u8>> {
+    let mut compressed = Vec::new();
+    compress_zopfli_stream(data, &mut compressed, options, block_type)?;
+    Ok(compressed)
+}
+
+// Size of the chunks --threads compresses at the same time: at least
+// PARALLEL_CHUNK_MIN, so the context lost at each boundary costs little
+fn parallel_chunk_size(len: usize, threads: usize) -> usize {
+    const PARALLEL_CHUNK_MIN: usize = 1 << 20;
+    len.div_ceil(threads.max(1)).max(PARALLEL_CHUNK_MIN)
+}
+
+// How many chunks, hence threads, --threads really gets out of `len` bytes
+fn threads_used(len: usize, threads: usize) -> usize {
+    if threads > 1 {
+        len.div_ceil(parallel_chunk_size(len, threads)).max(1)
+    } else {
+        1
+    }
+}
+
+// Compresses each `chunk_size` piece of `data` on its own thread. Each
+// becomes a gzip member of its own, which gzip -dc concatenates.
+fn compress_zopfli_parallel(data: &[u8], options: Options, block_type: BlockType,
+                            chunk_size: usize) -> io::Result<Vec<u8>> {
+    let results: Vec<io::Result<Vec<u8>>> = thread::scope(|scope| {
+        let handles: Vec<_> = data.chunks(chunk_size)
+            .map(|chunk| scope.spawn(move || compress_zopfli(chunk, options, block_type)))
+            .collect();
+        handles.into_iter()
+            .map(|h| h.join().unwrap_or_else(|_| Err(io::Error::other("compression thread panicked"))))
+            .collect()
+    });
+
+    let mut compressed = Vec::new();
+    for result in results {
+        compressed.extend_from_slice(&result?);
+    }
+    if data.is_empty() {
+        compressed = compress_zopfli(data, options, block_type)?;
+    }
+    Ok(compressed)
+}
+
+// Streaming variant: compresses `reader` into `writer` without holding the
+// input in memory. Returns the number of bytes read.
+fn compress_zopfli_stream<R: Read, W: Write>(mut reader: R, writer: W, options: Options,
+                                             block_type: BlockType) -> io::Result<u64> {
+    // Créer l'encodeur (tamponné pour compresser par blocs de taille maximale)
+    let mut encoder = GzipEncoder::new_buffered(options, block_type, writer)
+        .map_err(|e| io::Error::other(format!("Zopfli init error: {}", e)))?;
+    
+    // Écriture des données
+    let read = io::copy(&mut reader, &mut encoder)
+        .map_err(|e| io::Error::other(format!("Zopfli write error: {}", e)))?;
+    
+    // Finalisation
+    encoder.into_inner()
+        .map_err(|e| io::Error::other(format!("Zopfli write error: {}", e.error())))?
+        .finish()
+        .map_err(|e| io::Error::other(format!("Zopfli finish error: {}", e)))?;
+    
+    Ok(read)
+}
+
+// Ed25519 signature of the payload, made and checked with openssl. Both
+// fields are base64: the public key as DER SubjectPublicKeyInfo.
+#[derive(Debug)]
+struct Signature {
+    public_key: String,
+    value: String,
+}
+
+impl Signature {
+    fn placeholder() -> Signature {
+        Signature {
+            public_key: base64_encode(&[0; 44]),
+            value: base64_encode(&[0; 64]),
+        }
+    }
+
+    fn from_header(header: &[u8]) -> Option<Signature> {
+        Some(Signature {
+            public_key: header_value(header, SIGNER_PREFIX)?.to_string(),
+            value: header_value(header, SIGNATURE_PREFIX)?.to_string(),
+        })
+    }
+}
+
+fn sign_payload(key: &Path, payload: &Path) -> io::Result<Signature> {
+    let value = openssl_output(Command::new("openssl")
+        .args(["pkeyutl", "-sign", "-rawin", "-inkey"]).arg(key)
+        .arg("-in").arg(payload))?;
+    let public_key = openssl_output(Command::new("openssl")
+        .args(["pkey", "-pubout", "-outform", "DER", "-in"]).arg(key))?;
+
+    Ok(Signature {
+        public_key: base64_encode(&public_key),
+        value: base64_encode(&value),
+    })
+}
+
+// Checks the payload of a compressed file against its embedded signature,
+// or against `trusted_key` (a PEM public key) when given
+fn verify_signature(path: &Path, trusted_key: Option<&Path>) -> io::Result<Signature> {
+    let mut input = fs::File::open(path)?;
+    let header = read_header(&mut input)?;
+    let signature = Signature::from_header(&header)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "file is not signed"))?;
+    let value = base64_decode(&signature.value)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed signature"))?;
+
+    let (payload, mut payload_file) = create_temp_file("zexe-payload-")?;
+    let (sig, mut sig_file) = create_temp_file("zexe-sig-")?;
+    let (key, mut key_file) = create_temp_file("zexe-key-")?;
+    io::copy(&mut input, &mut payload_file)?;
+    sig_file.write_all(&value)?;
+    let key = match trusted_key {
+        Some(trusted) => trusted,
+        None => {
+            writeln!(key_file, "-----BEGIN PUBLIC KEY-----\n{}\n-----END PUBLIC KEY-----",
+                     signature.public_key)?;
+            key.path()
+        }
+    };
+    let status = Command::new("openssl")
+        .args(["pkeyutl", "-verify", "-pubin", "-rawin", "-inkey"]).arg(key)
+        .arg("-in").arg(payload.path())
+        .arg("-sigfile").arg(sig.path())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .map_err(openssl_error)?;
+
+    if !status.success() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+            "signature verification failed"));
+    }
+    Ok(signature)
+}
+
+fn verify_file(path: &Path, config: &Config) -> io::Result<Option<FileInfo>> {
+    if !is_compressed(path)? {
+        return Err(ZexeError::NotCompressed.into());
+    }
+
+    // Embedded and detached signatures are both checked when present
+    let embedded = Signature::from_header(&read_header(&mut fs::File::open(path)?)?).is_some();
+    let detached = detached_signature_path(path);
+    if embedded || !detached.exists() {
+        let signature = verify_signature(path, config.pubkey.as_deref())?;
+        if config.pubkey.is_some() {
+            println!("{}: signature OK (key {})", path.display(), signature.public_key);
+        } else {
+            // Whoever rewrites the payload can embed their own key as well
+            println!("{}: signature matches the embedded key {}, signer not authenticated",
+                     path.display(), signature.public_key);
+            eprintln!("{}: warning: this only shows the file is intact; use --pubkey to check who signed it",
+                      path.display());
+        }
+    }
+    if detached.exists() {
+        let signer = gpg_verify(path, &detached)?;
+        println!("{}: detached signature OK ({})", path.display(), signer);
+    }
+    Ok(None)
+}
+
+// Unpacks `path` without writing it anywhere and checks that it gives back,
+// byte for byte, the file it was packed from: its backup, or --compare-with
+fn compare_file(path: &Path, config: &Config) -> io::Result<Option<FileInfo>> {
+    if !is_compressed(path)? {
+        return Err(ZexeError::NotCompressed.into());
+    }
+
+    let reference = config.compare_with.clone().unwrap_or_else(|| backup_path(path, config));
+    let expected = fs::File::open(&reference)
+        .map_err(|e| io::Error::new(e.kind(), format!("cannot read {}: {}", reference.display(), e)))?;
+    let expected_size = expected.metadata()?.len();
+    let mut input = fs::File::open(path)?;
+    let header = read_header(&mut input)?;
+    if header_has(&header, BUNDLE_PREFIX) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+            "is a bundle; unpack it with -d and compare the files it writes"));
+    }
+    let mut unpacked = CompareWriter { expected: BufReader::new(expected), offset: 0, mismatch: None };
+    let unpacked_size = decode_payload(input, &header, config, &mut unpacked)?;
+    // The reference may go on after the unpacked file ends
+    if unpacked.mismatch.is_none() && !unpacked.expected.fill_buf()?.is_empty() {
+        unpacked.mismatch = Some(unpacked.offset);
+    }
+
+    match unpacked.mismatch {
+        None => {
+            println!("{}: matches {} ({} bytes)", path.display(), reference.display(), expected_size);
+            Ok(None)
+        }
+        Some(offset) => Err(io::Error::new(io::ErrorKind::InvalidData,
+            format!("differs from {} at byte {} (unpacks to {} bytes, {} has {})",
+                    reference.display(), offset, unpacked_size, reference.display(), expected_size))),
+    }
+}
+
+// Checks what is written to it against `expected` a buffer at a time and
+// keeps the offset of the first byte that differs
+struct CompareWriter<R> {
+    expected: R,
+    offset: u64,
+    mismatch: Option<u64>,
+}
+
+impl<R: BufRead> Write for CompareWriter<R> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut rest = buf;
+        while self.mismatch.is_none() && !rest.is_empty() {
+            let expected = self.expected.fill_buf()?;
+            let n = expected.len().min(rest.len());
+            if let Some(i) = first_difference(&rest[..n], &expected[..n]).or((n == 0).then_some(0)) {
+                self.mismatch = Some(self.offset + i as u64);
+                break;
+            }
+            self.expected.consume(n);
+            self.offset += n as u64;
+            rest = &rest[n..];
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Offset of the first byte that differs, or the length of the shorter one
+// when it is a prefix of the other
+fn first_difference(a: &[u8], b: &[u8]) -> Option<usize> {
+    a.iter().zip(b).position(|(x, y)| x != y)
+        .or_else(|| (a.len() != b.len()).then(|| a.len().min(b.len())))
+}
+
+// `path`.sig, next to the packed file, as gpg --detach-sign names it
+fn detached_signature_path(path: &Path) -> PathBuf {
+    let mut sig = path.as_os_str().to_owned();
+    sig.push(".sig");
+    PathBuf::from(sig)
+}
+
+// Writes a detached OpenPGP signature of the packed file for release
+// pipelines that check .sig files; gpg handles keys and the agent
+fn gpg_sign(path: &Path, key: &str) -> io::Result<()> {
+    let sig = detached_signature_path(path);
+    let output = Command::new("gpg")
+        .args(["--batch", "--yes", "--detach-sign", "--local-user", key, "--output"])
+        .arg(&sig)
+        .arg(path)
+        .stdin(Stdio::null())
+        .output()
+        .map_err(gpg_error)?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!("gpg failed: {}",
+            String::from_utf8_lossy(&output.stderr).lines().last().unwrap_or("unknown error"))));
+    }
+    Ok(())
+}
+
+// Checks `sig` against `path` and returns the signer's user ID
+fn gpg_verify(path: &Path, sig: &Path) -> io::Result<String> {
+    let output = Command::new("gpg")
+        .args(["--batch", "--status-fd", "1", "--verify"])
+        .arg(sig)
+        .arg(path)
+        .stdin(Stdio::null())
+        .output()
+        .map_err(gpg_error)?;
+    let status = String::from_utf8_lossy(&output.stdout);
+    let good = status.lines()
+        .find_map(|line| line.strip_prefix("[GNUPG:] GOODSIG "))
+        .and_then(|rest| rest.split_once(' '))
+        .map(|(_, user)| user.to_string());
+    match good {
+        Some(user) if output.status.success() => Ok(user),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData,
+            format!("bad detached signature {}", sig.display()))),
+    }
+}
+
+fn gpg_error(e: io::Error) -> io::Error {
+    if e.kind() == io::ErrorKind::NotFound {
+        io::Error::new(io::ErrorKind::NotFound, "gpg not found (required for --gpg-sign)")
+    } else {
+        e
+    }
+}
+
+fn openssl_output(command: &mut Command) -> io::Result<Vec<u8>> {
+    let output = command.stdin(Stdio::null()).output().map_err(openssl_error)?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!("openssl failed: {}",
+            String::from_utf8_lossy(&output.stderr).lines().next().unwrap_or("unknown error"))));
+    }
+    Ok(output.stdout)
+}
+
+// A temporary file that is removed when dropped, so that errors and early
+// returns leave nothing behind. defuse() once it has been moved into place.
+struct TempFile {
+    path: PathBuf,
+    armed: bool,
+}
+
+impl TempFile {
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn defuse(mut self) {
+        self.armed = false;
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if self.armed {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+// Creates a new file in the system temporary directory
+fn create_temp_file(prefix: &str) -> io::Result<(TempFile, fs::File)> {
+    create_temp_file_in(&env::temp_dir(), prefix)
+}
+
+// Creates a new file next to `path`, so the final rename stays on one
+// filesystem
+fn create_sibling_temp_file(path: &Path) -> io::Result<(TempFile, fs::File)> {
+    let dir = path.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    create_temp_file_in(dir, &format!(".{}.", name))
+}
+
+// create_new refuses to follow anything planted under the same name, and the
+// file is only readable by its owner until the final permissions are set.
+fn create_temp_file_in(dir: &Path, prefix: &str) -> io::Result<(TempFile, fs::File)> {
+    for attempt in 0..100 {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().subsec_nanos();
+        let path = dir.join(format!("{}{}-{}-{}", prefix, process::id(), nanos, attempt));
+        match new_file_options().read(true).write(true).open(&path) {
+            Ok(file) => return Ok((TempFile { path, armed: true }, file)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::AlreadyExists,
+        "could not create a temporary file"))
+}
+
+fn new_file_options() -> fs::OpenOptions {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options
+}
+
+// Puts the finished temp file in place of `path`. Callers set permissions
+// and xattrs on the temp file first, so `path` always names either the old
+// file or the complete new one, never a half-written or wrongly-moded file.
+// For that to hold across a crash, the temp file is synced before the
+// rename (contents and metadata) and the directory after it (the entry).
+// Only the copy fallback of move_file writes into `path`, and that fails
+// with ETXTBSY while the program runs: --wait retries until it exits,
+// otherwise the error says what to do.
+fn replace_file(temp: TempFile, path: &Path, config: &Config) -> io::Result<()> {
+    fs::File::open(temp.path())?.sync_all()?;
+    let timeout = if config.wait { BUSY_TIMEOUT } else { Duration::ZERO };
+    retry_while_busy(timeout, || move_file(temp.path(), path)).map_err(|e| {
+        if e.kind() == io::ErrorKind::ExecutableFileBusy {
+            io::Error::new(e.kind(), "the program is running; stop it and try again, or use --wait")
+        } else {
+            e
+        }
+    })?;
+    temp.defuse();
+    sync_parent_dir(path)
+}
+
+#[cfg(unix)]
+fn sync_parent_dir(path: &Path) -> io::Result<()> {
+    let dir = path.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    fs::File::open(dir)?.sync_all()
+}
+
+// Directories cannot be opened like files everywhere; the rename is left
+// to the filesystem
+#[cfg(not(unix))]
+fn sync_parent_dir(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+fn retry_while_busy<T>(timeout: Duration, mut op: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    let start = Instant::now();
+    loop {
+        match op() {
+            Err(e) if e.kind() == io::ErrorKind::ExecutableFileBusy && start.elapsed() < timeout => {
+                thread::sleep(BUSY_RETRY_INTERVAL);
+            }
+            result => return result,
+        }
+    }
+}
+
+// Renames `from` to `to`, or copies and removes it when they are on
+// different filesystems (a bind-mounted file, for example). fs::copy carries
+// the permissions over.
+fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            fs::copy(from, to)?;
+            fs::File::open(to)?.sync_all()?;
+            fs::remove_file(from)
+        }
+        result => result,
+    }
+}
+
+// Keeps the original as `backup` before `path` is replaced. A hard link is
+// atomic and costs nothing; the packed file is renamed over `path` so the
+// linked inode is never written to. Filesystems without hard links get an
+// exclusive copy. An existing backup is only replaced with --force, and then
+// unlinked first rather than written through, in case it is now a symlink.
+fn create_backup(path: &Path, backup: &Path, force: bool) -> io::Result<()> {
+    if force {
+        match fs::remove_file(backup) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+    match fs::hard_link(path, backup) {
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            return Err(ZexeError::BackupExists(backup.to_path_buf()).into());
+        }
+        Err(_) => {}
+        Ok(()) => return Ok(()),
+    }
+    let mut output = new_file_options().open(backup)?;
+    io::copy(&mut fs::File::open(path)?, &mut output)?;
+    output.set_permissions(fs::metadata(path)?.permissions())
+}
+
+// Extended attributes (capabilities, SELinux labels, user.*) as name/value
+// pairs. They are stored in the header so -d can put them back.
+type Xattrs = Vec<(String, Vec<u8>)>;
+
+// Linux file capabilities, as set by setcap(8)
+const CAPABILITY_XATTR: &str = "security.capability";
+
+// Applies `xattrs` to `target` on a best-effort basis: setting security.*
+// attributes usually needs privileges, so failures only warn.
+fn restore_xattrs(path: &Path, target: &Path, xattrs: &Xattrs) {
+    for (name, value) in xattrs {
+        if let Err(e) = set_xattr(target, name, value) {
+            if name == CAPABILITY_XATTR && e.kind() == io::ErrorKind::PermissionDenied {
+                eprintln!("{}: warning: could not restore its file capabilities (this needs CAP_SETFCAP, \
+                           usually root): {}", path.display(), e);
+                continue;
+            }
+            eprintln!("{}: warning: could not restore extended attribute {}: {}",
+                      path.display(), name, e);
+        }
+    }
+}
+
+// Whether this process may set file capabilities: CAP_SETFCAP (31) in the
+// effective set reported by /proc
+#[cfg(target_os = "linux")]
+fn has_setfcap() -> bool {
+    fs::read_to_string("/proc/self/status").ok()
+        .and_then(|status| status.lines()
+            .find_map(|line| line.strip_prefix("CapEff:"))
+            .and_then(|caps| u64::from_str_radix(caps.trim(), 16).ok()))
+        .is_some_and(|caps| caps & 1 << 31 != 0)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn has_setfcap() -> bool {
+    true
+}
+
+#[cfg(target_os = "linux")]
+mod xattr_sys {
+    use std::os::raw::{c_char, c_int, c_void};
+
+    pub const ERANGE: i32 = 34;
+    pub const ENODATA: i32 = 61;
+    pub const ENOTSUP: i32 = 95;
+
+    extern "C" {
+        pub fn listxattr(path: *const c_char, list: *mut c_char, size: usize) -> isize;
+        pub fn getxattr(path: *const c_char, name: *const c_char, value: *mut c_void,
+                        size: usize) -> isize;
+        pub fn setxattr(path: *const c_char, name: *const c_char, value: *const c_void,
+                        size: usize, flags: c_int) -> c_int;
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn read_xattrs(path: &Path) -> io::Result<Xattrs> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let cpath = CString::new(path.as_os_str().as_bytes())?;
+    let names = match xattr_buffer(|buf, len| unsafe {
+        xattr_sys::listxattr(cpath.as_ptr(), buf.cast(), len)
+    }) {
+        Ok(names) => names,
+        Err(e) if e.raw_os_error() == Some(xattr_sys::ENOTSUP) => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut xattrs = Vec::new();
+    for name in names.split(|&b| b == 0).filter(|n| !n.is_empty()) {
+        let cname = CString::new(name)?;
+        match xattr_buffer(|buf, len| unsafe {
+            xattr_sys::getxattr(cpath.as_ptr(), cname.as_ptr(), buf.cast(), len)
+        }) {
+            Ok(value) => xattrs.push((String::from_utf8_lossy(name).into_owned(), value)),
+            // Removed since it was listed
+            Err(e) if e.raw_os_error() == Some(xattr_sys::ENODATA) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(xattrs)
+}
+
+// Runs a query-size-then-fill xattr call, retrying if the value grew in between
+#[cfg(target_os = "linux")]
+fn xattr_buffer<F: Fn(*mut u8, usize) -> isize>(call: F) -> io::Result<Vec<u8>> {
+    loop {
+        let size = call(std::ptr::null_mut(), 0);
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut buf = vec![0u8; size as usize];
+        let len = call(buf.as_mut_ptr(), buf.len());
+        if len >= 0 {
+            buf.truncate(len as usize);
+            return Ok(buf);
+        }
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(xattr_sys::ERANGE) {
+            return Err(err);
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_xattr(path: &Path, name: &str, value: &[u8]) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let cpath = CString::new(path.as_os_str().as_bytes())?;
+    let cname = CString::new(name)?;
+    let ret = unsafe {
+        xattr_sys::setxattr(cpath.as_ptr(), cname.as_ptr(), value.as_ptr().cast(), value.len(), 0)
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn read_xattrs(_path: &Path) -> io::Result<Xattrs> {
+    Ok(Vec::new())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_xattr(_path: &Path, _name: &str, _value: &[u8]) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "extended attributes not supported"))
+}
+
+#[cfg(target_os = "linux")]
+mod mmap_sys {
+    use std::os::raw::{c_int, c_long, c_void};
+
+    pub const PROT_READ: c_int = 1;
+    pub const MAP_PRIVATE: c_int = 2;
+    pub const MAP_FAILED: *mut c_void = !0 as *mut c_void;
+
+    extern "C" {
+        pub fn mmap(addr: *mut c_void, len: usize, prot: c_int, flags: c_int, fd: c_int,
+                    offset: c_long) -> *mut c_void;
+        pub fn munmap(addr: *mut c_void, len: usize) -> c_int;
+    }
+}
+
+// The whole of a file, for the paths that compress it in one go (--auto,
+// --threads, --benchmark). A regular file is mapped read-only rather than
+// copied onto the heap; empty and special files, or a failed mmap, fall
+// back to fs::read.
+enum FileData {
+    #[cfg(target_os = "linux")]
+    Mapped(*mut std::os::raw::c_void, usize),
+    Read(Vec<u8>),
+}
+
+impl FileData {
+    fn read(path: &Path) -> io::Result<FileData> {
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::unix::io::AsRawFd;
+
+            let file = fs::File::open(path)?;
+            let metadata = file.metadata()?;
+            let len = metadata.len() as usize;
+            if metadata.is_file() && len > 0 {
+                let addr = unsafe {
+                    mmap_sys::mmap(std::ptr::null_mut(), len, mmap_sys::PROT_READ, mmap_sys::MAP_PRIVATE,
+                                   file.as_raw_fd(), 0)
+                };
+                if addr != mmap_sys::MAP_FAILED {
+                    return Ok(FileData::Mapped(addr, len));
+                }
+            }
+        }
+        fs::read(path).map(FileData::Read)
+    }
+}
+
+impl std::ops::Deref for FileData {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            #[cfg(target_os = "linux")]
+            FileData::Mapped(addr, len) => unsafe { std::slice::from_raw_parts(addr.cast(), *len) },
+            FileData::Read(data) => data,
+        }
+    }
+}
+
+impl Drop for FileData {
+    fn drop(&mut self) {
+        #[cfg(target_os = "linux")]
+        if let FileData::Mapped(addr, len) = *self {
+            unsafe { mmap_sys::munmap(addr, len) };
+        }
+    }
+}
+
+const BASE64_ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64_ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let text = text.trim_end_matches('=');
+    let mut out = Vec::with_capacity(text.len() * 3 / 4);
+    let mut n = 0u32;
+    for (i, c) in text.bytes().enumerate() {
+        let value = BASE64_ALPHABET.iter().position(|&a| a == c)? as u32;
+        n = n << 6 | value;
+        if i % 4 == 3 {
+            out.extend_from_slice(&n.to_be_bytes()[1..]);
+            n = 0;
+        }
+    }
+    match text.len() % 4 {
+        0 => {}
+        2 => out.push((n >> 4) as u8),
+        3 => out.extend_from_slice(&((n >> 2) as u16).to_be_bytes()),
+        _ => return None,
+    }
+    Some(out)
+}
+
+// The tests run the generated shell scripts and rely on Unix permissions
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    // An empty directory of its own for each test (and test process)
+    fn test_dir(name: &str) -> io::Result<PathBuf> {
+        let dir = env::temp_dir().join(format!("zexe_test_{}_{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir)?;
+        Ok(dir)
+    }
+
+    fn write_executable(path: &Path, content: impl AsRef<[u8]>) -> io::Result<()> {
+        fs::write(path, content)?;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o755))
+    }
+
+    fn test_config(files: Vec<PathBuf>) -> Config {
+        Config {
+            decompress: false,
+            benchmark: false,
+            gc: false,
+            info: false,
+            test: false,
+            dry_run: false,
+            json: false,
+            files,
+            compression_level: CompressionLevel::Normal,
+            level_map: Vec::new(),
+            default_args: Vec::new(),
+            auto: false,
+            store: false,
+            iterations: None,
+            iterations_without_improvement: None,
+            max_block_splits: None,
             block_type: BlockType::Dynamic,
+            threads: 1,
+            encrypt: false,
+            cache: false,
+            extract_only: false,
+            data: false,
+            no_xattr: false,
+            no_sparse: false,
+            max_output: None,
+            cksum_check: false,
+            recursive: false,
+            output_dir: None,
+            bundle: None,
+            entry: None,
+            include: Vec::new(),
+            exclude: Vec::new(),
+            force: false,
+            fail_fast: false,
+            backup_suffix: None,
+            wait: false,
+            allow_setuid: false,
+            preserve_caps: false,
+            // Most fixtures are small shell scripts
+            any: true,
+            dereference: false,
+            min_ratio: None,
+            strip: false,
+            sign_key: None,
+            gpg_key: None,
+            verify_sig: false,
+            compare: false,
+            compare_with: None,
+            pubkey: None,
+            password_file: None,
             verbose: false,
+        }
+    }
+
+    #[test]
+    fn test_compress_decompress() -> io::Result<()> {
+        let test_file = env::temp_dir().join("zexe_test");
+        fs::write(&test_file, b"#!/bin/sh\necho 'Hello World'\n")?;
+        
+        let mut perms = fs::metadata(&test_file)?.permissions();
+        perms.set_mode(0o755);
+        fs::set_permissions(&test_file, perms)?;
+
+        let config = test_config(vec![test_file.clone()]);
+
+        compress_file(&test_file, &config)?;
+        assert!(is_compressed(&test_file)?);
+
+        // Test execution of compressed file
+        use std::process::Command;
+        let output = Command::new(&test_file).output()?;
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"Hello World\n");
+
+        decompress_file(&test_file, &config)?;
+        assert!(!is_compressed(&test_file)?);
+
+        fs::remove_file(&test_file)?;
+        fs::remove_file(test_file.with_extension("~"))?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_tiny_files() -> io::Result<()> {
+        let dir = test_dir("tiny_files")?;
+
+        let mut block = b"#!/bin/sh\n".to_vec();
+        block.resize(HEADER_SIZE - 1, b'#');
+        block.push(b'\n');
+        let inputs: [(&str, &[u8]); 2] = [("newline", b"\n"), ("block", &block)];
+        let modes = [
+            ("zopfli", Config { compression_level: CompressionLevel::Fast, ..test_config(Vec::new()) }),
+            // -v prints a ratio, which used to overflow on files this small
+            ("stored", Config { store: true, verbose: true, ..test_config(Vec::new()) }),
+            ("checked", Config { compression_level: CompressionLevel::Fast, cksum_check: true,
+                                 ..test_config(Vec::new()) }),
+        ];
+        for (mode, config) in &modes {
+            for (name, content) in inputs {
+                let test_file = dir.join(format!("{}-{}", name, mode));
+                write_executable(&test_file, content)?;
+
+                let info = compress_file(&test_file, config)?.unwrap();
+                assert_eq!(info.original_size, content.len() as u64);
+                assert!(info.compression_ratio().is_finite());
+                let output = Command::new(&test_file).output()?;
+                assert!(output.status.success(), "{} {}: {:?}", name, mode, output);
+
+                decompress_file(&test_file, &Config { force: true, ..test_config(Vec::new()) })?;
+                assert_eq!(fs::read(&test_file)?, content, "{} {}", name, mode);
+            }
+        }
+
+        // Empty files are refused, and a payload cut short is reported as such
+        let test_file = dir.join("empty");
+        write_executable(&test_file, b"")?;
+        let err = compress_file(&test_file, &test_config(Vec::new())).unwrap_err();
+        assert_eq!(ZexeError::of(&err), Some(&ZexeError::Empty));
+        fs::write(&test_file, b"\n")?;
+        compress_file(&test_file, &Config { compression_level: CompressionLevel::Fast, ..test_config(Vec::new()) })?;
+        let packed = fs::read(&test_file)?;
+        fs::write(&test_file, &packed[..packed.len() - 8])?;
+        let err = decompress_file(&test_file, &test_config(Vec::new())).unwrap_err();
+        assert!(err.to_string().contains("too short"), "{}", err);
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_stored_payload() -> io::Result<()> {
+        let test_file = env::temp_dir().join(format!("zexe_test_stored_{}", process::id()));
+        let content = b"#!/bin/sh\necho stored\n";
+        write_executable(&test_file, content)?;
+
+        let config = Config { store: true, force: true, ..test_config(Vec::new()) };
+        compress_file(&test_file, &config)?;
+        let packed = fs::read(&test_file)?;
+        assert!(packed.ends_with(content));
+        assert!(header_has(&packed, STORED_MARKER));
+        assert_eq!(Command::new(&test_file).output()?.stdout, b"stored\n");
+
+        // Nothing but the cksum catches a damaged stored payload
+        let damaged = test_file.with_extension("damaged");
+        let mut bytes = packed.clone();
+        *bytes.last_mut().unwrap() = b'?';
+        write_executable(&damaged, bytes)?;
+        let output = Command::new(&damaged).output()?;
+        assert!(!output.status.success());
+        assert!(String::from_utf8_lossy(&output.stderr).contains("checksum mismatch"));
+        fs::remove_file(&damaged)?;
+
+        // Without the marker, the payload is not taken for gzip by default
+        let unmarked = test_file.with_extension("unmarked");
+        let at = packed.windows(STORED_MARKER.len()).position(|w| w == STORED_MARKER).unwrap();
+        let mut bytes = packed.clone();
+        bytes[at..at + STORED_MARKER.len()].fill(b'#');
+        fs::write(&unmarked, bytes)?;
+        let err = decompress_file(&unmarked, &config).unwrap_err();
+        assert!(err.to_string().contains("neither gzip"), "{}", err);
+        fs::remove_file(&unmarked)?;
+
+        decompress_file(&test_file, &config)?;
+        assert_eq!(fs::read(&test_file)?, content);
+
+        // --auto falls back to storing data that does not compress
+        let mut noise = b"#!/bin/sh\nexit 0\n".to_vec();
+        let mut x: u32 = 0x2545_f491;
+        for _ in 0..4096 {
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            noise.push(x as u8);
+        }
+        fs::write(&test_file, &noise)?;
+        let config = Config { store: false, auto: true, ..config };
+        compress_file(&test_file, &config)?;
+        assert!(header_has(&fs::read(&test_file)?, STORED_MARKER));
+        decompress_file(&test_file, &config)?;
+        assert_eq!(fs::read(&test_file)?, noise);
+
+        assert!(parse_args(&args(&["--store", "--max", "a"])).is_err());
+
+        fs::remove_file(&test_file)?;
+        fs::remove_file(test_file.with_extension("~"))?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_encrypted_compress_decompress() -> io::Result<()> {
+        if Command::new("openssl").arg("version").output().is_err() {
+            return Ok(());
+        }
+
+        let test_file = env::temp_dir().join("zexe_test_encrypted");
+        write_executable(&test_file, b"#!/bin/sh\necho 'Hello Secret'\n")?;
+        let correct_file = env::temp_dir().join("zexe_test_encrypted_correct");
+        fs::write(&correct_file, "correct horse\n")?;
+
+        let config = Config {
+            compression_level: CompressionLevel::Fast,
+            encrypt: true,
+            password_file: Some(correct_file.clone()),
+            ..test_config(vec![test_file.clone()])
+        };
+
+        compress_file(&test_file, &config)?;
+        assert!(is_compressed(&test_file)?);
+
+        let output = Command::new(&test_file).env(PASS_ENV, "correct horse").output()?;
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"Hello Secret\n");
+
+        let output = Command::new(&test_file).env(PASS_ENV, "wrong").output()?;
+        assert!(!output.status.success());
+
+        // The password file wins over $ZEXE_PASS, in the script and with -d
+        let pass_file = env::temp_dir().join("zexe_test_encrypted_pass");
+        fs::write(&pass_file, "correct horse\n")?;
+        let output = Command::new(&test_file).env(PASS_ENV, "wrong")
+            .env(PASS_FILE_ENV, &pass_file).output()?;
+        assert_eq!(output.stdout, b"Hello Secret\n");
+        fs::write(&pass_file, "wrong\n")?;
+        let wrong = Config { password_file: Some(pass_file.clone()), ..test_config(Vec::new()) };
+        assert!(decompress_file(&test_file, &wrong).is_err());
+        fs::remove_file(&pass_file)?;
+        assert!(!has_temp_files(&test_file)?);
+
+        decompress_file(&test_file, &config)?;
+        assert_eq!(fs::read(&test_file)?, b"#!/bin/sh\necho 'Hello Secret'\n");
+
+        fs::remove_file(&correct_file)?;
+        fs::remove_file(&test_file)?;
+        fs::remove_file(test_file.with_extension("~"))?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_dry_run_leaves_file_untouched() -> io::Result<()> {
+        let test_file = env::temp_dir().join("zexe_test_dry_run");
+        let content = b"#!/bin/sh\necho 'Hello Dry Run'\n";
+        write_executable(&test_file, content)?;
+        let _ = fs::remove_file(test_file.with_extension("~"));
+
+        let config = Config { dry_run: true, ..test_config(vec![test_file.clone()]) };
+        let info = compress_file(&test_file, &config)?.unwrap();
+
+        assert_eq!(fs::read(&test_file)?, content);
+        assert!(!test_file.with_extension("~").exists());
+        assert!(!has_temp_files(&test_file)?);
+        assert_eq!(info.original_size, content.len() as u64);
+
+        let config = Config { dry_run: false, ..config };
+        let real = compress_file(&test_file, &config)?.unwrap();
+        assert_eq!(info.compressed_size, real.compressed_size);
+
+        let packed = fs::read(&test_file)?;
+        let config = Config { dry_run: true, decompress: true, ..config };
+        let info = decompress_file(&test_file, &config)?.unwrap();
+        assert_eq!(fs::read(&test_file)?, packed);
+        assert!(!has_temp_files(&test_file)?);
+        assert_eq!(info.original_size, content.len() as u64);
+        assert_eq!(info.compressed_size, real.compressed_size);
+
+        fs::remove_file(&test_file)?;
+        fs::remove_file(test_file.with_extension("~"))?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_posix_cksum() -> io::Result<()> {
+        // Reference values from coreutils `cksum`
+        assert_eq!(posix_cksum(&b""[..])?, Cksum { crc: 4294967295, size: 0 });
+        assert_eq!(posix_cksum(&b"hello\n"[..])?, Cksum { crc: 3015617425, size: 6 });
+        assert_eq!(posix_cksum(&b"123456789"[..])?, Cksum { crc: 930766865, size: 9 });
+        assert_eq!(posix_cksum(&b"123456789"[..])?.to_string(), "930766865 9");
+        Ok(())
+    }
+
+    #[test]
+    fn test_original_name_kept_as_argv0() -> io::Result<()> {
+        let dir = test_dir("argv0")?;
+        let cache_home = dir.join("cache-home");
+
+        for cache in [false, true] {
+            let test_file = dir.join("multi call");
+            write_executable(&test_file, b"#!/bin/sh\necho \"${0##*/}\"\n")?;
+            let config = Config { cache, force: true, ..test_config(vec![test_file.clone()]) };
+            compress_file(&test_file, &config)?;
+
+            fs::rename(&test_file, dir.join("renamed"))?;
+            let output = Command::new(dir.join("renamed")).env("XDG_CACHE_HOME", &cache_home).output()?;
+            assert!(output.status.success());
+            assert_eq!(output.stdout, b"multi call\n");
+        }
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_cached_compressed_file_runs() -> io::Result<()> {
+        let test_file = env::temp_dir().join("zexe_test_cache");
+        let cache_home = env::temp_dir().join("zexe_test_cache_home");
+        let _ = fs::remove_dir_all(&cache_home);
+        write_executable(&test_file, b"#!/bin/sh\necho 'Hello Cache'\n")?;
+
+        let config = Config { cache: true, ..test_config(vec![test_file.clone()]) };
+        compress_file(&test_file, &config)?;
+
+        for _ in 0..2 {
+            let output = Command::new(&test_file).env("XDG_CACHE_HOME", &cache_home).output()?;
+            assert!(output.status.success());
+            assert_eq!(output.stdout, b"Hello Cache\n");
+            assert_eq!(fs::read_dir(cache_home.join("zexe"))?.count(), 1);
+        }
+
+        fs::remove_dir_all(&cache_home)?;
+        fs::remove_file(&test_file)?;
+        fs::remove_file(test_file.with_extension("~"))?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_gc_temp_dirs() -> io::Result<()> {
+        let dir = test_dir("gc")?;
+        fs::create_dir_all(dir.join("zexe.AbCdEf0123"))?;
+        fs::create_dir_all(dir.join("zexe.short"))?;
+        fs::create_dir_all(dir.join("other.AbCdEf0123"))?;
+        fs::write(dir.join("zexe.FileNot123"), b"")?;
+
+        assert_eq!(gc_temp_dirs(&dir, STALE_TEMP_AGE, false)?, 0);
+        assert_eq!(gc_temp_dirs(&dir, Duration::ZERO, false)?, 1);
+        assert!(!dir.join("zexe.AbCdEf0123").exists());
+        assert!(dir.join("zexe.short").exists());
+        assert!(dir.join("other.AbCdEf0123").exists());
+        assert!(dir.join("zexe.FileNot123").exists());
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_extract_only() -> io::Result<()> {
+        let dir = test_dir("extract_only")?;
+        let test_file = dir.join("installer");
+        let content = b"#!/bin/sh\necho 'should not run'\n";
+        write_executable(&test_file, content)?;
+
+        let config = Config { extract_only: true, ..test_config(vec![test_file.clone()]) };
+        compress_file(&test_file, &config)?;
+        fs::rename(&test_file, dir.join("setup"))?;
+
+        // Default target: the original name in the current directory
+        let output = Command::new(dir.join("setup")).current_dir(&dir).output()?;
+        assert!(output.status.success());
+        assert!(output.stdout.ends_with(b"extracted to installer\n"));
+        assert_eq!(fs::read(dir.join("installer"))?, content);
+
+        // Never overwrite, explicit target otherwise
+        assert!(!Command::new(dir.join("setup")).current_dir(&dir).status()?.success());
+        let status = Command::new(dir.join("setup")).arg(dir.join("copy")).status()?;
+        assert!(status.success());
+        assert_eq!(fs::read(dir.join("copy"))?, content);
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_data_file() -> io::Result<()> {
+        let dir = test_dir("data")?;
+        let test_file = dir.join("notes.txt");
+        let content = b"plain text, not a program\n".repeat(20);
+        fs::write(&test_file, &content)?;
+        fs::set_permissions(&test_file, fs::Permissions::from_mode(0o640))?;
+
+        assert!(compress_file(&test_file, &test_config(Vec::new())).is_err());
+        let config = Config { data: true, extract_only: true, ..test_config(Vec::new()) };
+        compress_file(&test_file, &config)?;
+        assert_eq!(fs::metadata(&test_file)?.permissions().mode() & 0o777, 0o740);
+
+        let output = Command::new(&test_file).arg(dir.join("out.txt")).output()?;
+        assert!(output.status.success(), "{:?}", output);
+        assert_eq!(fs::read(dir.join("out.txt"))?, content);
+        assert_eq!(fs::metadata(dir.join("out.txt"))?.permissions().mode() & 0o100, 0);
+
+        decompress_file(&test_file, &config)?;
+        assert_eq!(fs::read(&test_file)?, content);
+        assert_eq!(fs::metadata(&test_file)?.permissions().mode() & 0o777, 0o640);
+
+        // A renamed archive still writes the file under its original name
+        let packed = dir.join("foo.dat");
+        fs::write(&packed, &content)?;
+        compress_file(&packed, &config)?;
+        let mut input = fs::File::open(&packed)?;
+        assert_eq!(header_value(&read_header(&mut input)?, NAME_PREFIX), Some("foo.dat"));
+        fs::rename(&packed, dir.join("foo.run"))?;
+        assert!(Command::new(dir.join("foo.run")).current_dir(&dir).status()?.success());
+        assert_eq!(fs::read(&packed)?, content);
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_runs_under_posix_shells() -> io::Result<()> {
+        let dir = test_dir("posix_shells")?;
+
+        let shells: Vec<Vec<&str>> = vec![vec!["dash"], vec!["busybox", "sh"], vec!["bash", "--posix"]];
+        let modes = [
+            ("temp", test_config(Vec::new())),
+            ("cache", Config { cache: true, ..test_config(Vec::new()) }),
+            ("stored", Config { store: true, ..test_config(Vec::new()) }),
+            ("checked", Config { cksum_check: true, ..test_config(Vec::new()) }),
+        ];
+        for (name, config) in modes {
+            let packed = dir.join(format!("prog-{}", name));
+            write_executable(&packed, b"#!/bin/sh\necho \"$# $1\"\n")?;
+            compress_file(&packed, &config)?;
+
+            for shell in &shells {
+                let Ok(output) = Command::new(shell[0]).args(&shell[1..]).arg(&packed).arg(name)
+                    .env("XDG_CACHE_HOME", dir.join("cache-home"))
+                    .output() else {
+                    continue; // shell not installed
+                };
+                assert!(output.status.success(), "{:?} {}: {:?}", shell, name, output);
+                assert_eq!(output.stdout, format!("1 {}\n", name).as_bytes(), "{:?}", shell);
+                assert!(output.stderr.is_empty(), "{:?}: {}", shell, String::from_utf8_lossy(&output.stderr));
+            }
+        }
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_exit_status_propagates() -> io::Result<()> {
+        let dir = test_dir("exit_status")?;
+        let password = dir.join("password");
+        fs::write(&password, "exit status\n")?;
+
+        let mut modes = vec![
+            ("temp", test_config(Vec::new())),
+            ("cache", Config { cache: true, ..test_config(Vec::new()) }),
+            ("stored", Config { store: true, ..test_config(Vec::new()) }),
+            ("checked", Config { cksum_check: true, ..test_config(Vec::new()) }),
+        ];
+        if Command::new("openssl").arg("version").output().is_ok() {
+            modes.push(("encrypted", Config { encrypt: true, password_file: Some(password.clone()),
+                                              ..test_config(Vec::new()) }));
+        }
+        let script = b"#!/bin/sh\necho \"$1\"\nexit 42\n";
+        for (name, config) in modes {
+            let packed = dir.join(name);
+            write_executable(&packed, script)?;
+            let config = Config { compression_level: CompressionLevel::Fast, ..config };
+            compress_file(&packed, &config)?;
+
+            let output = Command::new(&packed).arg(name)
+                .env(PASS_FILE_ENV, &password)
+                .env("XDG_CACHE_HOME", dir.join("cache-home"))
+                .output()?;
+            assert_eq!(output.status.code(), Some(42), "{}: {:?}", name, output);
+            assert_eq!(output.stdout, format!("{}\n", name).as_bytes());
+        }
+
+        // A bundle execs its member the same way
+        let tool = dir.join("tool");
+        write_executable(&tool, script)?;
+        let bundle = dir.join("bundle");
+        bundle_files(&bundle, &[tool], &Config { compression_level: CompressionLevel::Fast,
+                                                 ..test_config(Vec::new()) })?;
+        assert_eq!(Command::new(&bundle).status()?.code(), Some(42));
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_default_args() -> io::Result<()> {
+        let dir = test_dir("default_args")?;
+
+        let Action::Run(config) = parse_args(&args(&["--args", "--config /etc/app.conf", "--args",
+                                                    r#"'$(id>pwned)' "it's" -m\ 'a b' """#, "-1", "prog"]))? else {
+            panic!("expected a run");
+        };
+        assert_eq!(config.default_args, ["--config", "/etc/app.conf", "$(id>pwned)", "it's", "-m a b", ""]);
+        assert!(parse_args(&args(&["--args", "-v", "--data", "prog"])).is_err());
+        assert!(parse_args(&args(&["--args", "it's", "prog"])).is_err());
+        assert_eq!(split_args(r#"a"b c"'d'\e "\$x\y""#), Some(vec!["ab cde".to_string(), "$x\\y".to_string()]));
+
+        for (name, cache) in [("temp", false), ("cache", true)] {
+            let packed = dir.join(name);
+            write_executable(&packed, b"#!/bin/sh\nfor a in \"$@\"; do echo \"[$a]\"; done\n")?;
+            let config = Config { cache, default_args: config.default_args.clone(), ..test_config(Vec::new()) };
+            compress_file(&packed, &config)?;
+
+            let output = Command::new(&packed).arg("given")
+                .current_dir(&dir)
+                .env("XDG_CACHE_HOME", dir.join("cache-home"))
+                .output()?;
+            assert_eq!(String::from_utf8_lossy(&output.stdout),
+                       "[--config]\n[/etc/app.conf]\n[$(id>pwned)]\n[it's]\n[-m a b]\n[]\n[given]\n", "{}", name);
+            assert!(!dir.join("pwned").exists());
+        }
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_paths_and_arguments_with_spaces() -> io::Result<()> {
+        let dir = test_dir("has space")?;
+        fs::create_dir_all(dir.join("tmp dir"))?;
+
+        for (name, config) in [("temp", test_config(Vec::new())),
+                               ("cache", Config { cache: true, ..test_config(Vec::new()) })] {
+            let packed = dir.join(format!("my prog {}", name));
+            write_executable(&packed, b"#!/bin/sh\nfor a in \"$@\"; do echo \"[$a]\"; done\n")?;
+            compress_file(&packed, &config)?;
+
+            let output = Command::new(&packed)
+                .args(["--name", "a b", "*", "$HOME", ""])
+                .env("TMPDIR", dir.join("tmp dir"))
+                .env("XDG_CACHE_HOME", dir.join("cache dir"))
+                .output()?;
+            assert!(output.status.success(), "{}: {:?}", name, output);
+            assert_eq!(output.stdout, b"[--name]\n[a b]\n[*]\n[$HOME]\n[]\n", "{}", name);
+        }
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_shell_quote() -> io::Result<()> {
+        for s in ["plain", "with space", "it's", "$HOME `id` \"q\""] {
+            let output = Command::new("sh").arg("-c").arg(format!("printf %s {}", shell_quote(s))).output()?;
+            assert_eq!(output.stdout, s.as_bytes());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_signed_compressed_file() -> io::Result<()> {
+        let dir = test_dir("sign")?;
+        let key = dir.join("key.pem");
+        let generated = Command::new("openssl")
+            .args(["genpkey", "-algorithm", "ed25519", "-out"]).arg(&key)
+            .stderr(Stdio::null())
+            .status();
+        if !generated.is_ok_and(|s| s.success()) {
+            return Ok(());
+        }
+
+        let test_file = dir.join("prog");
+        write_executable(&test_file, b"#!/bin/sh\necho 'Hello Signed'\n")?;
+        let config = Config { sign_key: Some(key.clone()), ..test_config(vec![test_file.clone()]) };
+        let dry = compress_file(&test_file, &Config { dry_run: true, sign_key: Some(key), ..test_config(Vec::new()) })?;
+        let info = compress_file(&test_file, &config)?.unwrap();
+        assert_eq!(dry.unwrap().compressed_size, info.compressed_size);
+
+        let output = Command::new(&test_file).output()?;
+        assert_eq!(output.stdout, b"Hello Signed\n");
+        verify_signature(&test_file, None)?;
+
+        // The verified copy is what runs, and it is gone afterwards, also
+        // on a cache hit where nothing is extracted
+        let tmp = dir.join("tmp");
+        fs::create_dir(&tmp)?;
+        let cached = dir.join("cached");
+        write_executable(&cached, b"#!/bin/sh\necho 'Hello Cached'\n")?;
+        compress_file(&cached, &Config { cache: true, sign_key: config.sign_key.clone(), ..test_config(Vec::new()) })?;
+        for (file, expected) in [(&test_file, &b"Hello Signed\n"[..]), (&cached, b"Hello Cached\n"), (&cached, b"Hello Cached\n")] {
+            let output = Command::new(file).env("TMPDIR", &tmp).env("XDG_CACHE_HOME", dir.join("cache")).output()?;
+            assert_eq!(output.stdout, expected);
+            for entry in fs::read_dir(&tmp)? {
+                assert!(!entry?.path().join("payload").exists());
+            }
+        }
+
+        // A pinned key other than the signer's is rejected
+        let other = dir.join("other.pem");
+        Command::new("openssl").args(["genpkey", "-algorithm", "ed25519", "-out"]).arg(&other).status()?;
+        Command::new("openssl").args(["pkey", "-pubout", "-in"]).arg(&other)
+            .arg("-out").arg(dir.join("other.pub")).status()?;
+        assert!(verify_signature(&test_file, Some(&dir.join("other.pub"))).is_err());
+
+        // Flip a bit in the gzip trailer
+        let mut packed = fs::read(&test_file)?;
+        *packed.last_mut().unwrap() ^= 1;
+        fs::write(&test_file, &packed)?;
+        let output = Command::new(&test_file).output()?;
+        assert!(!output.status.success());
+        assert!(output.stdout.is_empty());
+        assert!(verify_signature(&test_file, None).is_err());
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_decompress_keeps_holes() -> io::Result<()> {
+        let test_file = env::temp_dir().join(format!("zexe_test_sparse_{}", process::id()));
+        let mut content = b"#!/bin/sh\necho sparse\nexit 0\n".to_vec();
+        content.resize(1 << 20, 0);
+        content.extend_from_slice(b"tail");
+        content.resize(2 << 20, 0);
+        write_executable(&test_file, &content)?;
+
+        let config = Config { compression_level: CompressionLevel::Fast, ..test_config(Vec::new()) };
+        compress_file(&test_file, &config)?;
+        decompress_file(&test_file, &config)?;
+        assert_eq!(fs::read(&test_file)?, content);
+        let sparse_blocks = fs::metadata(&test_file)?.blocks();
+
+        fs::write(&test_file, &content)?;
+        compress_file(&test_file, &Config { force: true, ..config })?;
+        decompress_file(&test_file, &Config { no_sparse: true, ..test_config(Vec::new()) })?;
+        assert_eq!(fs::read(&test_file)?, content);
+        let dense_blocks = fs::metadata(&test_file)?.blocks();
+        // Filesystems without holes store both the same way
+        assert!(sparse_blocks <= dense_blocks / 4 || sparse_blocks == dense_blocks);
+
+        fs::remove_file(&test_file)?;
+        fs::remove_file(test_file.with_extension("~"))?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_base64() {
+        for (plain, encoded) in [(&b""[..], ""), (b"f", "Zg=="), (b"fo", "Zm8="), (b"foo", "Zm9v"),
+                                 (b"foob", "Zm9vYg=="), (b"\x00\xff\x10", "AP8Q")] {
+            assert_eq!(base64_encode(plain), encoded);
+            assert_eq!(base64_decode(encoded).as_deref(), Some(plain));
+        }
+        assert_eq!(base64_decode("Z"), None);
+        assert_eq!(base64_decode("Zm9v!"), None);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_xattrs_preserved() -> io::Result<()> {
+        let test_file = env::temp_dir().join("zexe_test_xattr");
+        let content = b"#!/bin/sh\necho 'Hello Xattr'\n";
+        write_executable(&test_file, content)?;
+        if set_xattr(&test_file, "user.zexe.test", b"\x00kept").is_err() {
+            // Filesystem without user xattrs
+            fs::remove_file(&test_file)?;
+            return Ok(());
+        }
+
+        let config = test_config(vec![test_file.clone()]);
+        compress_file(&test_file, &config)?;
+        assert!(read_xattrs(&test_file)?.contains(&("user.zexe.test".to_string(), b"\x00kept".to_vec())));
+
+        // -d restores from the header even if the packed file lost them
+        fs::copy(&test_file, test_file.with_extension("copy"))?;
+        fs::rename(test_file.with_extension("copy"), &test_file)?;
+        assert!(read_xattrs(&test_file)?.is_empty());
+        decompress_file(&test_file, &config)?;
+        assert_eq!(fs::read(&test_file)?, content);
+        assert!(read_xattrs(&test_file)?.contains(&("user.zexe.test".to_string(), b"\x00kept".to_vec())));
+
+        fs::remove_file(&test_file)?;
+        fs::remove_file(test_file.with_extension("~"))?;
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_xattr_name_is_not_run() -> io::Result<()> {
+        let test_file = env::temp_dir().join(format!("zexe_test_xattr_name_{}", process::id()));
+        write_executable(&test_file, b"#!/bin/sh\necho 'Hello Xattr'\n")?;
+        if set_xattr(&test_file, "user.a\necho INJECTED >&2\n#", b"v").is_err() {
+            // Filesystem without user xattrs
+            return fs::remove_file(&test_file);
+        }
+
+        compress_file(&test_file, &Config { store: true, ..test_config(Vec::new()) })?;
+        let header = read_header(&mut fs::File::open(&test_file)?)?;
+        assert!(!header.windows(8).any(|w| w == b"INJECTED"));
+        let output = Command::new(&test_file).output()?;
+        assert_eq!(output.stdout, b"Hello Xattr\n");
+        assert!(!String::from_utf8_lossy(&output.stderr).contains("INJECTED"));
+
+        fs::remove_file(&test_file)?;
+        fs::remove_file(test_file.with_extension("~"))?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_data() -> io::Result<()> {
+        let test_file = env::temp_dir().join(format!("zexe_test_file_data_{}", process::id()));
+        let content: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        fs::write(&test_file, &content)?;
+        let data = FileData::read(&test_file)?;
+        #[cfg(target_os = "linux")]
+        assert!(matches!(data, FileData::Mapped(..)));
+        assert_eq!(&data[..], &content[..]);
+        drop(data);
+
+        // Nothing to map
+        fs::write(&test_file, b"")?;
+        assert!(matches!(FileData::read(&test_file)?, FileData::Read(ref data) if data.is_empty()));
+        assert!(matches!(FileData::read(Path::new("/dev/null"))?, FileData::Read(_)));
+
+        fs::remove_file(&test_file)
+    }
+
+    #[test]
+    fn test_header_fields() -> io::Result<()> {
+        let original = Original {
+            name: "it's".to_string(),
+            mode: 0o755,
+            cksum: posix_cksum(&b"123456789"[..])?,
+            xattrs: vec![("user.a b".to_string(), vec![0, 255, 10])],
+        };
+        let header = generate_header(&Config { cache: true, ..test_config(Vec::new()) }, &original, false, None)?;
+        assert_eq!(parse_header_cksum(&header), Some(original.cksum));
+        assert_eq!(header_value(&header, NAME_PREFIX), Some("it's"));
+        assert_eq!(parse_header_xattrs(&header), original.xattrs);
+        assert!(header_has(&header, CACHED_MARKER));
+        assert!(!header_has(&header, ENCRYPTED_MARKER));
+        Ok(())
+    }
+
+    // True if a sibling temporary file of `path` was left behind
+    fn has_temp_files(path: &Path) -> io::Result<bool> {
+        let prefix = format!(".{}.", path.file_name().unwrap().to_string_lossy());
+        for entry in fs::read_dir(path.parent().unwrap())? {
+            if entry?.file_name().to_string_lossy().starts_with(&prefix) {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    #[test]
+    fn test_packs_real_binary() -> io::Result<()> {
+        let Some(true_bin) = ["/bin/true", "/usr/bin/true"].into_iter().map(Path::new).find(|p| p.exists()) else {
+            return Ok(());
+        };
+        let dir = test_dir("real_binary")?;
+        let original = fs::read(true_bin)?;
+
+        let modes = [
+            // Minimal effort: a debug build of Zopfli is slow on a whole binary
+            ("zopfli", Config { compression_level: CompressionLevel::Custom, iterations: NonZeroU64::new(1),
+                                iterations_without_improvement: NonZeroU64::new(1), max_block_splits: Some(1),
+                                any: false, ..test_config(Vec::new()) }),
+            ("stored", Config { store: true, any: false, ..test_config(Vec::new()) }),
+        ];
+        for (name, config) in modes {
+            let test_file = dir.join(format!("true-{}", name));
+            fs::copy(true_bin, &test_file)?;
+            compress_file(&test_file, &config)?;
+            assert!(is_compressed(&test_file)?);
+
+            let status = Command::new(&test_file).arg("--ignored").status()?;
+            assert_eq!(status.code(), Some(0), "{}", name);
+
+            decompress_file(&test_file, &config)?;
+            assert_eq!(fs::read(&test_file)?, original, "{}", name);
+        }
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_force_repacks_packed_file() -> io::Result<()> {
+        let dir = test_dir("repack")?;
+        let test_file = dir.join("prog");
+        let content = format!("#!/bin/sh\n{}", "echo repacked\n".repeat(50));
+        fs::write(&test_file, &content)?;
+        fs::set_permissions(&test_file, fs::Permissions::from_mode(0o750))?;
+        compress_file(&test_file, &Config { store: true, ..test_config(Vec::new()) })?;
+        let stored = fs::read(&test_file)?;
+
+        let config = Config { compression_level: CompressionLevel::Fast, ..test_config(Vec::new()) };
+        let err = compress_file(&test_file, &config).unwrap_err();
+        assert_eq!(ZexeError::of(&err), Some(&ZexeError::AlreadyCompressed("stored".to_string())));
+        assert!(err.to_string().contains("-d") && err.to_string().contains("--force"));
+
+        // Unpacked and packed again, not wrapped twice; the backup still
+        // holds the original
+        let config = Config { force: true, ..config };
+        compress_file(&test_file, &config)?;
+        let header = read_header(&mut fs::File::open(&test_file)?)?;
+        assert!(!header_has(&header, STORED_MARKER));
+        assert_ne!(fs::read(&test_file)?, stored);
+        assert_eq!(fs::read_to_string(test_file.with_extension("~"))?, content);
+        assert!(compare_file(&test_file, &Config { compare: true, ..test_config(Vec::new()) })?.is_none());
+
+        // Without a backup, the unpacked original becomes one
+        fs::remove_file(test_file.with_extension("~"))?;
+        compress_file(&test_file, &config)?;
+        assert_eq!(fs::read_to_string(test_file.with_extension("~"))?, content);
+        assert_eq!(fs::metadata(&test_file)?.mode() & 0o777, 0o750);
+        let output = Command::new(&test_file).output()?;
+        assert!(output.stdout.starts_with(b"repacked\n"));
+
+        decompress_file(&test_file, &Config { force: true, ..test_config(Vec::new()) })?;
+        assert_eq!(fs::read_to_string(&test_file)?, content);
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_failed_decompress_keeps_packed_file() -> io::Result<()> {
+        let dir = test_dir("failed_decompress")?;
+        let test_file = dir.join("prog");
+        write_executable(&test_file, format!("#!/bin/sh\n{}", "echo partial\n".repeat(2000)))?;
+        compress_file(&test_file, &Config { compression_level: CompressionLevel::Fast, ..test_config(Vec::new()) })?;
+
+        // Corrupt the end of the payload: most of the output is written
+        // to the temp file before the gzip trailer check fails
+        let mut packed = fs::read(&test_file)?;
+        let crc = packed.len() - 8;
+        packed[crc] ^= 0xff;
+        fs::write(&test_file, &packed)?;
+
+        let err = decompress_file(&test_file, &test_config(Vec::new())).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(fs::read(&test_file)?, packed);
+        assert!(!has_temp_files(&test_file)?);
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_compare() -> io::Result<()> {
+        let dir = test_dir("compare")?;
+        let test_file = dir.join("prog");
+        let content = format!("#!/bin/sh\n{}", "echo compare\n".repeat(100));
+        write_executable(&test_file, &content)?;
+        let config = Config { compression_level: CompressionLevel::Fast, ..test_config(Vec::new()) };
+        compress_file(&test_file, &config)?;
+
+        let config = Config { compare: true, ..config };
+        assert!(compare_file(&test_file, &config)?.is_none());
+
+        let backup = test_file.with_extension("~");
+        let mut changed = content.clone().into_bytes();
+        changed[20] ^= 1;
+        fs::write(&backup, &changed)?;
+        let err = compare_file(&test_file, &config).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("at byte 20"), "{}", err);
+        for reference in [format!("{}x", content), content[..content.len() - 1].to_string()] {
+            fs::write(&backup, &reference)?;
+            let err = compare_file(&test_file, &config).unwrap_err();
+            let offset = reference.len().min(content.len());
+            assert!(err.to_string().contains(&format!("at byte {} ", offset)), "{}", err);
+        }
+
+        fs::write(dir.join("copy"), &content)?;
+        let config = Config { compare_with: Some(dir.join("copy")), ..config };
+        assert!(compare_file(&test_file, &config)?.is_none());
+        assert!(is_compressed(&test_file)?);
+
+        // Unpacked files and missing backups are errors, not matches
+        assert!(compare_file(&backup, &config).is_err());
+        fs::remove_file(&backup)?;
+        assert!(compare_file(&test_file, &Config { compare_with: None, ..config }).is_err());
+
+        assert_eq!(first_difference(b"abc", b"abc"), None);
+        assert_eq!(first_difference(b"abc", b"abd"), Some(2));
+        assert_eq!(first_difference(b"ab", b"abc"), Some(2));
+        assert_eq!(first_difference(b"", b"a"), Some(0));
+
+        assert!(parse_args(&args(&["--compare", "--compare-with", "x", "a"])).is_ok());
+        assert!(parse_args(&args(&["--compare-with", "x", "a"])).is_err());
+        assert!(parse_args(&args(&["--compare", "--compare-with", "x", "a", "b"])).is_err());
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_integrity_check() -> io::Result<()> {
+        let dir = test_dir("integrity")?;
+        let test_file = dir.join("prog");
+        let content = format!("#!/bin/sh\n{}", "echo intact\n".repeat(100));
+        for config in [Config { compression_level: CompressionLevel::Fast, ..test_config(Vec::new()) },
+                       Config { store: true, ..test_config(Vec::new()) }] {
+            write_executable(&test_file, &content)?;
+            compress_file(&test_file, &Config { force: true, ..config })?;
+            let packed = fs::read(&test_file)?;
+
+            let config = Config { test: true, ..test_config(Vec::new()) };
+            assert!(test_integrity(&test_file, &config)?.is_none());
+            assert_eq!(fs::read(&test_file)?, packed);
+
+            // A stored payload has no gzip CRC: only the cksum catches this
+            let mut damaged = packed.clone();
+            let header_len = read_header(&mut fs::File::open(&test_file)?)?.len();
+            let middle = header_len + (packed.len() - header_len) / 2;
+            damaged[middle] ^= 1;
+            fs::write(&test_file, &damaged)?;
+            let err = test_integrity(&test_file, &config).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{}", err);
+            assert_eq!(fs::read(&test_file)?, damaged);
+
+            fs::write(&test_file, &packed[..packed.len() - 10])?;
+            assert_eq!(test_integrity(&test_file, &config).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+
+        let err = test_integrity(&test_file.with_extension("~"), &test_config(Vec::new())).unwrap_err();
+        assert_eq!(ZexeError::of(&err), Some(&ZexeError::NotCompressed));
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_unpack_files_in_parallel() -> io::Result<()> {
+        let dir = test_dir("unpack_parallel")?;
+        let config = Config { store: true, ..test_config(Vec::new()) };
+        let mut files = Vec::new();
+        for i in 0..5 {
+            let path = dir.join(format!("prog{}", i));
+            write_executable(&path, format!("#!/bin/sh\necho {}\n", i))?;
+            // One file left unpacked fails to unpack
+            if i != 2 {
+                compress_file(&path, &config)?;
+            }
+            files.push(Input { path, relative: PathBuf::new() });
+        }
+
+        let config = Config { decompress: true, threads: 3, ..config };
+        let outcomes = unpack_files(&files, &config);
+        assert_eq!(outcomes.len(), files.len());
+        for (i, (outcome, input)) in outcomes.into_iter().zip(&files).enumerate() {
+            let (result, _) = outcome.expect("every file is started without --fail-fast");
+            if i == 2 {
+                assert_eq!(ZexeError::of(&result.unwrap_err()), Some(&ZexeError::NotCompressed));
+            } else {
+                assert_eq!(result?.unwrap().path, input.path);
+                assert_eq!(fs::read_to_string(&input.path)?, format!("#!/bin/sh\necho {}\n", i));
+            }
+        }
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_failed_compress_leaves_no_temp_file() -> io::Result<()> {
+        let dir = test_dir("failed_compress")?;
+        let test_file = dir.join("prog");
+        write_executable(&test_file, b"#!/bin/sh\necho ok\n")?;
+
+        // openssl fails on the missing passphrase file after the temp file exists
+        let config = Config { encrypt: true, store: true, password_file: Some(dir.join("missing")),
+                              ..test_config(Vec::new()) };
+        assert!(compress_file(&test_file, &config).is_err());
+        assert!(!has_temp_files(&test_file)?);
+        assert!(!is_compressed(&test_file)?);
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_bundle() -> io::Result<()> {
+        let dir = test_dir("bundle")?;
+        fs::create_dir_all(dir.join("bin"))?;
+        fs::create_dir_all(dir.join("out"))?;
+        let tools = ["main", "convert"].map(|name| dir.join("bin").join(name));
+        for tool in &tools {
+            fs::write(tool, format!("#!/bin/sh\necho \"{}: $*\"\n", tool.file_name().unwrap().to_string_lossy()))?;
+            fs::set_permissions(tool, fs::Permissions::from_mode(0o750))?;
+        }
+        let out = dir.join("out/tools.run");
+        let config = Config { compression_level: CompressionLevel::Fast, entry: Some("convert".to_string()),
+                              ..test_config(Vec::new()) };
+
+        bundle_files(&out, &tools, &config)?;
+        let output = Command::new(&out).args(["a", "b"]).output()?;
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "convert: a b\n");
+        std::os::unix::fs::symlink("tools.run", dir.join("out/main"))?;
+        let output = Command::new(dir.join("out/main")).arg("c").output()?;
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "main: c\n");
+
+        // The output, an unknown entry and clashing names are refused
+        assert!(bundle_files(&out, &tools, &config).is_err());
+        let bad_entry = Config { entry: Some("nope".to_string()), force: true, ..test_config(Vec::new()) };
+        assert!(bundle_files(&out, &tools, &bad_entry).is_err());
+        let config = Config { force: true, ..config };
+        assert!(bundle_files(&out, &[tools[0].clone(), tools[0].clone()], &config).is_err());
+
+        // -d writes the members next to the bundle with their modes
+        fs::remove_file(dir.join("out/main"))?;
+        decompress_file(&out, &config)?;
+        for tool in &tools {
+            let unpacked = dir.join("out").join(tool.file_name().unwrap());
+            assert_eq!(fs::read(&unpacked)?, fs::read(tool)?);
+            assert_eq!(fs::metadata(&unpacked)?.mode() & 0o777, 0o750);
+        }
+        assert!(is_compressed(&out)?);
+        let err = decompress_file(&out, &Config { force: false, ..config }).unwrap_err();
+        assert!(matches!(ZexeError::of(&err), Some(ZexeError::OutputExists(_))));
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_decompress_plain_gzip() -> io::Result<()> {
+        let dir = test_dir("plain_gzip")?;
+        let gz = dir.join("notes.txt.gz");
+        let content = "plain gzip, no zexe header\n".repeat(20);
+        let mut encoder = flate2::write::GzEncoder::new(fs::File::create(&gz)?, flate2::Compression::best());
+        encoder.write_all(content.as_bytes())?;
+        encoder.finish()?;
+
+        let info = decompress_file(&gz, &test_config(Vec::new()))?.unwrap();
+        assert_eq!(info.path, dir.join("notes.txt"));
+        assert_eq!(fs::read_to_string(dir.join("notes.txt"))?, content);
+        assert!(gz.exists());
+        let err = decompress_file(&gz, &test_config(Vec::new())).unwrap_err();
+        assert!(matches!(ZexeError::of(&err), Some(ZexeError::OutputExists(_))));
+
+        // Only .gz names with gzip data take this path
+        let err = decompress_file(&dir.join("notes.txt"), &test_config(Vec::new())).unwrap_err();
+        assert_eq!(ZexeError::of(&err), Some(&ZexeError::NotCompressed));
+        fs::write(dir.join("fake.gz"), "not gzip")?;
+        let err = decompress_file(&dir.join("fake.gz"), &test_config(Vec::new())).unwrap_err();
+        assert_eq!(ZexeError::of(&err), Some(&ZexeError::NotCompressed));
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_tar_rejects_paths() {
+        let dir = env::temp_dir().join(format!("zexe_test_tar_{}", process::id()));
+        fs::write(&dir, "x").unwrap();
+        let mut tar = write_tar(&[("ok".to_string(), dir.as_path())]).unwrap();
+        fs::remove_file(&dir).unwrap();
+        let members = read_tar(&tar).unwrap();
+        assert_eq!(members.len(), 1);
+        assert_eq!((members[0].0.as_str(), members[0].2), ("ok", &b"x"[..]));
+
+        tar[..3].copy_from_slice(b"../");
+        assert!(read_tar(&tar).is_err());
+        assert!(read_tar(&tar[..512]).is_err());
+    }
+
+    #[test]
+    fn test_benchmark_leaves_file_alone() -> io::Result<()> {
+        let test_file = env::temp_dir().join(format!("zexe_test_benchmark_{}", process::id()));
+        let content = b"#!/bin/sh\necho benchmark benchmark benchmark\n";
+        write_executable(&test_file, content)?;
+
+        assert!(benchmark_file(&test_file, &test_config(Vec::new()))?.is_none());
+        assert_eq!(fs::read(&test_file)?, content);
+        assert!(!has_temp_files(&test_file)?);
+
+        fs::remove_file(&test_file)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_json_string() {
+        assert_eq!(json_string("bin/zexe"), "\"bin/zexe\"");
+        assert_eq!(json_string("a \"b\"\\c"), r#""a \"b\"\\c""#);
+        assert_eq!(json_string("tab\tnew\nline"), r#""tab\u0009new\nline""#);
+    }
+
+    #[test]
+    fn test_max_output() -> io::Result<()> {
+        assert_eq!(parse_size("4096"), Some(4096));
+        assert_eq!(parse_size("64k"), Some(64 << 10));
+        assert_eq!(parse_size("2G"), Some(2 << 30));
+        assert_eq!(parse_size("M"), None);
+        assert_eq!(parse_size("99999999999G"), None);
+
+        let dir = test_dir("max_output")?;
+        let test_file = dir.join("prog");
+        let content = format!("#!/bin/sh\n{}", "echo bomb\n".repeat(100));
+        write_executable(&test_file, &content)?;
+        let config = Config { compression_level: CompressionLevel::Fast, ..test_config(Vec::new()) };
+        compress_file(&test_file, &config)?;
+
+        let err = decompress_file(&test_file, &Config { max_output: Some(100), ..test_config(Vec::new()) })
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("past 100 bytes"), "{}", err);
+        assert!(is_compressed(&test_file)?);
+        assert!(!has_temp_files(&test_file)?);
+
+        decompress_file(&test_file, &Config { force: true, ..config })?;
+        assert_eq!(fs::read_to_string(&test_file)?, content);
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_hard_linked_file() -> io::Result<()> {
+        let dir = test_dir("hard_linked")?;
+        let test_file = dir.join("prog");
+        let other = dir.join("other");
+        let content = "#!/bin/sh\necho linked\n";
+        write_executable(&test_file, content)?;
+        fs::hard_link(&test_file, &other)?;
+
+        let config = Config { store: true, ..test_config(vec![test_file.clone()]) };
+        let err = compress_file(&test_file, &config).unwrap_err();
+        assert_eq!(ZexeError::of(&err), Some(&ZexeError::HardLinked(1)));
+        assert!(!is_compressed(&test_file)?);
+
+        // Forced, only the given name is packed
+        compress_file(&test_file, &Config { force: true, ..config })?;
+        assert!(is_compressed(&test_file)?);
+        assert_eq!(fs::read_to_string(&other)?, content);
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_backup_does_not_follow_symlink() -> io::Result<()> {
+        let dir = test_dir("backup_symlink")?;
+        let test_file = dir.join("prog");
+        let victim = dir.join("victim");
+        write_executable(&test_file, format!("#!/bin/sh\n{}", "echo packed\n".repeat(50)))?;
+        fs::write(&victim, "untouched")?;
+        std::os::unix::fs::symlink(&victim, test_file.with_extension("~"))?;
+
+        let config = test_config(vec![test_file.clone()]);
+        let err = compress_file(&test_file, &config).unwrap_err();
+        assert_eq!(ZexeError::of(&err), Some(&ZexeError::BackupExists(test_file.with_extension("~"))));
+        assert!(err.to_string().contains("--force"));
+        assert!(!is_compressed(&test_file)?);
+
+        compress_file(&test_file, &Config { force: true, ..config })?;
+        assert_eq!(fs::read_to_string(&victim)?, "untouched");
+        assert!(fs::read_to_string(test_file.with_extension("~"))?.starts_with("#!/bin/sh\necho packed"));
+        assert!(!has_temp_files(&test_file)?);
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_strip_copy() -> io::Result<()> {
+        if Command::new("strip").arg("--version").output().is_err() {
+            return Ok(()); // binutils not installed
+        }
+        // Test binaries are built with symbols
+        let exe = env::current_exe()?;
+        let stripped = strip_copy(&exe, &test_config(Vec::new()))?;
+        assert!(fs::metadata(stripped.path())?.len() < fs::metadata(&exe)?.len());
+        assert_eq!(read_binary_format(stripped.path())?, Some(BinaryFormat::Elf));
+        let path = stripped.path().to_path_buf();
+        drop(stripped);
+        assert!(!path.exists());
+
+        let script = env::temp_dir().join(format!("zexe_test_strip_{}", process::id()));
+        fs::write(&script, b"#!/bin/sh\n")?;
+        assert_eq!(strip_copy(&script, &test_config(Vec::new())).err().map(|e| e.kind()), Some(io::ErrorKind::InvalidInput));
+        fs::remove_file(&script)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_backup_suffix() -> io::Result<()> {
+        let dir = test_dir("backup_suffix")?;
+        let test_file = dir.join("tool.sh");
+        write_executable(&test_file, b"#!/bin/sh\necho ok\n")?;
+
+        let config = Config { backup_suffix: Some(".orig".into()), ..test_config(Vec::new()) };
+        assert_eq!(backup_path(&test_file, &test_config(Vec::new())), dir.join("tool.~"));
+        assert_eq!(backup_path(&test_file, &config), dir.join("tool.sh.orig"));
+        compress_file(&test_file, &config)?;
+        assert_eq!(fs::read(dir.join("tool.sh.orig"))?, b"#!/bin/sh\necho ok\n");
+        assert!(is_backup(&dir.join("tool.sh.orig"), &config));
+
+        // Never silently overwritten
+        decompress_file(&test_file, &config)?;
+        let err = compress_file(&test_file, &config).unwrap_err();
+        assert!(matches!(ZexeError::of(&err), Some(ZexeError::BackupExists(_))));
+
+        assert!(parse_args(&args(&["--backup-suffix", "a/b", "x"])).is_err());
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_expand_files_recursive() -> io::Result<()> {
+        let dir = test_dir("recursive")?;
+        fs::create_dir_all(dir.join("sub"))?;
+        let elf = [&b"\x7fELF"[..], &[0; 500]].concat();
+        for (name, content, mode) in [
+            ("a", &elf[..], 0o755),
+            ("sub/b", &elf[..], 0o755),
+            ("data", &elf[..], 0o644),
+            ("script", &b"#!/bin/sh\n"[..], 0o755),
+            ("setuid", &elf[..], 0o4755),
+        ] {
+            fs::write(dir.join(name), content)?;
+            fs::set_permissions(dir.join(name), fs::Permissions::from_mode(mode))?;
+        }
+        std::os::unix::fs::symlink("a", dir.join("link"))?;
+
+        let paths = |config: &Config| -> io::Result<Vec<PathBuf>> {
+            Ok(expand_files(config)?.into_iter().map(|input| input.path).collect())
         };
+        let config = Config { recursive: true, any: false, ..test_config(vec![dir.clone()]) };
+        assert_eq!(paths(&config)?, vec![dir.join("a"), dir.join("sub/b")]);
+        let filtered = Config { include: vec!["*".into()], exclude: vec!["sub/*".into()], ..config };
+        assert_eq!(paths(&filtered)?, vec![dir.join("a")]);
+        let filtered = Config { include: vec!["b".into(), "*/b".into()], exclude: Vec::new(), ..filtered };
+        assert_eq!(paths(&filtered)?, vec![dir.join("sub/b")]);
+        let config = Config { include: Vec::new(), ..filtered };
+
+        compress_file(&dir.join("a"), &config)?;
+        assert_eq!(paths(&config)?, vec![dir.join("sub/b")]);
+        let config = Config { decompress: true, ..config };
+        assert_eq!(paths(&config)?, vec![dir.join("a")]);
+
+        // Without --recursive the directory is kept and reported by run()
+        let config = Config { recursive: false, ..config };
+        assert_eq!(paths(&config)?, vec![dir.clone()]);
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_output_dir() -> io::Result<()> {
+        let dir = test_dir("output_dir")?;
+        fs::create_dir_all(dir.join("in/sub"))?;
+        let content = b"#!/bin/sh\necho ok\n";
+        for name in ["in/a", "in/sub/b"] {
+            write_executable(&dir.join(name), content)?;
+        }
+
+        let out = dir.join("out");
+        let config = Config { recursive: true, output_dir: Some(out.clone()), ..test_config(vec![dir.join("in")]) };
+        let files = expand_files(&config)?;
+        assert_eq!(files.iter().map(|f| f.relative.clone()).collect::<Vec<_>>(),
+                   vec![PathBuf::from("a"), PathBuf::from("sub/b")]);
+        for input in &files {
+            compress_file_to(&input.path, Some(&out.join(&input.relative)), &config)?;
+        }
+
+        // Inputs are left alone, without backups
+        assert_eq!(fs::read(dir.join("in/sub/b"))?, content);
+        assert!(!dir.join("in/sub/b~").exists());
+        let packed = out.join("sub/b");
+        assert!(is_compressed(&packed)?);
+        assert_eq!(fs::metadata(&packed)?.permissions().mode() & 0o777, 0o755);
+        assert!(!has_temp_files(&packed)?);
+
+        let err = compress_file_to(&dir.join("in/sub/b"), Some(&packed), &config).unwrap_err();
+        assert!(matches!(ZexeError::of(&err), Some(ZexeError::OutputExists(_))));
+        compress_file_to(&dir.join("in/sub/b"), Some(&packed), &Config { force: true, ..config })?;
+
+        // Two inputs that would land on the same output are refused up front
+        fs::copy(dir.join("in/a"), dir.join("in/sub/a"))?;
+        let config = test_config(vec![dir.join("in/a"), dir.join("in/sub/b")]);
+        check_output_paths(&expand_files(&config)?, &out)?;
+        let config = test_config(vec![dir.join("in/a"), dir.join("in/sub/a")]);
+        let err = check_output_paths(&expand_files(&config)?, &out).unwrap_err();
+        assert_eq!(err.to_string(), format!("{} and {} would both be written to {}", dir.join("in/a").display(),
+                                            dir.join("in/sub/a").display(), out.join("a").display()));
+
+        assert!(parse_args(&args(&["--output-dir", "out", "-d", "a"])).is_err());
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("*.bin", "tool.bin"));
+        assert!(glob_match("*.bin", "sub/tool.bin"));
+        assert!(!glob_match("*.bin", "tool.bin.old"));
+        assert!(glob_match("debug/*", "debug/x/y"));
+        assert!(!glob_match("debug/*", "release/debug"));
+        assert!(glob_match("t?ol", "tool"));
+        assert!(glob_match("[a-c]x[!0-9]", "bxy"));
+        assert!(!glob_match("[a-c]x[!0-9]", "bx1"));
+        assert!(glob_match("[]]", "]"));
+        assert!(glob_match("a[b", "a[b"));
+        assert!(glob_match("*a*b*", "xxaxxbxx"));
+        assert!(!glob_match("*a*b", "xxbxxa"));
+        assert!(glob_match("**", ""));
+    }
+
+    #[test]
+    fn test_allow_setuid_keeps_mode() -> io::Result<()> {
+        let test_file = env::temp_dir().join(format!("zexe_test_setuid_{}", process::id()));
+        fs::write(&test_file, format!("#!/bin/sh\n{}", "echo setuid\n".repeat(50)))?;
+        fs::set_permissions(&test_file, fs::Permissions::from_mode(0o4755))?;
+        if fs::metadata(&test_file)?.mode() & 0o4000 == 0 {
+            // nosuid mount
+            return fs::remove_file(&test_file);
+        }
 
+        let config = test_config(Vec::new());
+        assert!(compress_file(&test_file, &config).is_err());
+        assert!(!is_compressed(&test_file)?);
+
+        let config = Config { allow_setuid: true, ..config };
         compress_file(&test_file, &config)?;
         assert!(is_compressed(&test_file)?);
+        assert_eq!(fs::metadata(&test_file)?.mode() & 0o7777, 0o4755);
+        decompress_file(&test_file, &config)?;
+        assert_eq!(fs::metadata(&test_file)?.mode() & 0o7777, 0o4755);
 
-        // Test execution of compressed file
-        use std::process::Command;
-        let output = Command::new(&test_file).output()?;
-        assert!(output.status.success());
-        assert_eq!(output.stdout, b"Hello World\n");
+        fs::remove_file(&test_file)?;
+        fs::remove_file(test_file.with_extension("~"))?;
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_preserve_caps() -> io::Result<()> {
+        let test_file = env::temp_dir().join(format!("zexe_test_caps_{}", process::id()));
+        write_executable(&test_file, format!("#!/bin/sh\n{}", "echo caps\n".repeat(50)))?;
+        // VFS_CAP_REVISION_2 with cap_net_raw permitted and effective
+        let caps = [&[1, 0, 0, 2, 0, 0x20, 0, 0][..], &[0; 12]].concat();
+        if set_xattr(&test_file, CAPABILITY_XATTR, &caps).is_err() {
+            // Not root, or no xattrs here
+            return fs::remove_file(&test_file);
+        }
 
-        decompress_file(&test_file)?;
+        let config = test_config(Vec::new());
+        let err = compress_file(&test_file, &config).unwrap_err();
+        assert_eq!(ZexeError::of(&err), Some(&ZexeError::Capabilities));
         assert!(!is_compressed(&test_file)?);
 
+        // Like any other xattr, -d puts them back
+        compress_file(&test_file, &Config { force: true, ..test_config(Vec::new()) })?;
+        assert!(is_compressed(&test_file)?);
+        decompress_file(&test_file, &config)?;
+        assert!(read_xattrs(&test_file)?.contains(&(CAPABILITY_XATTR.to_string(), caps.clone())));
+        fs::remove_file(test_file.with_extension("~"))?;
+
+        // Kept in the header even with --no-xattr, but not on the packed script
+        let config = Config { preserve_caps: true, no_xattr: true, ..config };
+        compress_file(&test_file, &config)?;
+        assert!(is_compressed(&test_file)?);
+        assert!(read_xattrs(&test_file)?.is_empty());
+        decompress_file(&test_file, &config)?;
+        assert!(read_xattrs(&test_file)?.contains(&(CAPABILITY_XATTR.to_string(), caps)));
+
         fs::remove_file(&test_file)?;
         fs::remove_file(test_file.with_extension("~"))?;
         Ok(())
     }
 
+    #[test]
+    fn test_scripts_need_any() -> io::Result<()> {
+        let test_file = env::temp_dir().join(format!("zexe_test_any_{}", process::id()));
+        let content = format!("#!/bin/sh\n{}", "echo script\n".repeat(50));
+        write_executable(&test_file, &content)?;
+
+        let config = Config { any: false, ..test_config(Vec::new()) };
+        let err = compress_file(&test_file, &config).unwrap_err();
+        assert_eq!(ZexeError::of(&err), Some(&ZexeError::NotBinary));
+        assert!(err.to_string().contains("--any"));
+        assert_eq!(fs::read_to_string(&test_file)?, content);
+
+        fs::write(&test_file, [&b"\x7fELF"[..], &[0; 500]].concat())?;
+        assert!(compress_file(&test_file, &Config { dry_run: true, ..config })?.is_some());
+
+        fs::remove_file(&test_file)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_missing_runtime_tools_reported() -> io::Result<()> {
+        let dir = test_dir("missing_tools")?;
+        fs::create_dir_all(dir.join("bin"))?;
+        let test_file = dir.join("prog");
+        write_executable(&test_file, b"#!/bin/sh\necho 'never runs'\n")?;
+        compress_file(&test_file, &test_config(Vec::new()))?;
+
+        // Nothing on PATH: gzip is looked up first
+        let output = Command::new(&test_file).env("PATH", dir.join("bin")).output()?;
+        assert_eq!(output.status.code(), Some(1));
+        assert!(String::from_utf8_lossy(&output.stderr).contains("'gzip' not found"));
+
+        // gzip alone is not enough for an encrypted payload
+        let gzip = Command::new("sh").args(["-c", "command -v gzip"]).output()?.stdout;
+        let gzip = String::from_utf8_lossy(&gzip).trim().to_string();
+        if Command::new("openssl").arg("version").output().is_ok() && !gzip.is_empty() {
+            let password = dir.join("password");
+            fs::write(&password, "correct horse\n")?;
+            let test_file = dir.join("secret");
+            write_executable(&test_file, b"#!/bin/sh\necho 'never runs'\n")?;
+            compress_file(&test_file, &Config { encrypt: true, password_file: Some(password),
+                                                ..test_config(Vec::new()) })?;
+
+            std::os::unix::fs::symlink(gzip, dir.join("bin/gzip"))?;
+            let output = Command::new(&test_file).env("PATH", dir.join("bin")).output()?;
+            assert_eq!(output.status.code(), Some(1));
+            assert!(String::from_utf8_lossy(&output.stderr).contains("'openssl' not found"));
+        }
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_symlinks_need_dereference() -> io::Result<()> {
+        let dir = test_dir("symlink")?;
+        let target = dir.join("target");
+        let link = dir.join("link");
+        let content = format!("#!/bin/sh\n{}", "echo linked\n".repeat(50));
+        write_executable(&target, &content)?;
+        std::os::unix::fs::symlink("target", &link)?;
+
+        let config = test_config(vec![link.clone()]);
+        let err = compress_file(&link, &config).unwrap_err();
+        assert_eq!(ZexeError::of(&err), Some(&ZexeError::Symlink));
+        assert_eq!(fs::read_to_string(&target)?, content);
+
+        let config = Config { dereference: true, ..config };
+        compress_file(&link, &config)?;
+        assert!(fs::symlink_metadata(&link)?.file_type().is_symlink());
+        assert!(is_compressed(&target)?);
+        assert_eq!(Command::new(&link).output()?.stdout, "linked\n".repeat(50).as_bytes());
+
+        decompress_file(&link, &config)?;
+        assert!(fs::symlink_metadata(&link)?.file_type().is_symlink());
+        assert_eq!(fs::read_to_string(&target)?, content);
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_retry_while_busy() {
+        let busy = || io::Error::from(io::ErrorKind::ExecutableFileBusy);
+        let mut attempts = 0;
+        let result = retry_while_busy(Duration::from_secs(5), || {
+            attempts += 1;
+            if attempts < 3 { Err(busy()) } else { Ok(attempts) }
+        });
+        assert_eq!(result.unwrap(), 3);
+
+        let mut attempts = 0;
+        let result: io::Result<()> = retry_while_busy(Duration::ZERO, || {
+            attempts += 1;
+            Err(busy())
+        });
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::ExecutableFileBusy);
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn test_move_file_across_filesystems() -> io::Result<()> {
+        let name = format!("zexe_test_move_{}", process::id());
+        let to = env::temp_dir().join(&name);
+        for dir in [env::temp_dir(), PathBuf::from("/dev/shm")] {
+            let from = dir.join(format!("{}.src", name));
+            if fs::write(&from, b"moved").is_err() {
+                continue;
+            }
+            fs::set_permissions(&from, fs::Permissions::from_mode(0o750))?;
+            move_file(&from, &to)?;
+            assert!(!from.exists());
+            assert_eq!(fs::read(&to)?, b"moved");
+            assert_eq!(fs::metadata(&to)?.permissions().mode() & 0o777, 0o750);
+            fs::remove_file(&to)?;
+        }
+        Ok(())
+    }
+
+    fn args(list: &[&str]) -> Vec<String> {
+        std::iter::once("zexe").chain(list.iter().copied()).map(String::from).collect()
+    }
+
+    #[test]
+    fn test_completion_options() {
+        for (name, value) in OPTIONS {
+            let mut list = vec![*name];
+            match value {
+                OptionValue::None => {}
+                OptionValue::Words(words) => list.push(words.split(' ').next().unwrap()),
+                _ => list.push("1"),
+            }
+            list.extend(["-r", "prog"]);
+            if let Err(e) = parse_args(&args(&list)) {
+                assert!(!e.to_string().starts_with("Unknown option"), "{}: {}", name, e);
+            }
+        }
+        assert!(matches!(parse_args(&args(&["--completions", "fish"])), Ok(Action::Completions(_))));
+        assert!(matches!(parse_args(&args(&["--selftest"])), Ok(Action::SelfTest)));
+        assert!(parse_args(&args(&["--completions", "tcsh"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_args_actions() -> io::Result<()> {
+        assert!(matches!(parse_args(&args(&["-h"]))?, Action::Help));
+        assert!(matches!(parse_args(&args(&["--help", "prog"]))?, Action::Help));
+        assert!(matches!(parse_args(&args(&["-V"]))?, Action::Version { verbose: false }));
+        assert!(matches!(parse_args(&args(&["--version", "-v"]))?, Action::Version { verbose: true }));
+        match parse_args(&args(&["-d", "-n", "prog"]))? {
+            Action::Run(config) => {
+                assert!(config.decompress && config.dry_run);
+                assert_eq!(config.files, vec![PathBuf::from("prog")]);
+            }
+            action => panic!("unexpected {:?}", action),
+        }
+        assert!(parse_args(&args(&["--bogus", "prog"])).is_err());
+        assert!(parse_args(&args(&[])).is_err());
+        assert!(parse_args(&args(&["--cache", "--encrypt", "prog"])).is_err());
+        assert!(parse_args(&args(&["--cache", "--no-cache", "--encrypt", "prog"])).is_ok());
+        assert!(matches!(parse_args(&args(&["--no-exec", "prog"]))?, Action::Run(config) if config.extract_only));
+
+        let level = |list: &[&str]| parse_args(&args(list)).map(|action| match action {
+            Action::Run(config) => config.compression_level,
+            action => panic!("unexpected {:?}", action),
+        });
+        assert_eq!(level(&["prog"])?, CompressionLevel::Normal);
+        assert_eq!(level(&["--max", "prog"])?, CompressionLevel::Maximum);
+        assert_eq!(level(&["--compress-level", "balanced", "-2", "prog"])?, CompressionLevel::Normal);
+        assert_eq!(level(&["--iterations", "9", "--max-block-splits", "3", "prog"])?, CompressionLevel::Custom);
+        assert!(level(&["-1", "--ultra", "prog"]).is_err());
+        assert!(level(&["--ultra", "--iterations", "9", "prog"]).is_err());
+        assert!(level(&["--compress-level", "9", "prog"]).is_err());
+
+        let Action::Run(config) = parse_args(&args(&["--map", ".so=ultra", "--map", "sh=1", "-r", "dir"]))? else {
+            panic!("expected a run");
+        };
+        assert_eq!(file_level(Path::new("dir/libfoo.so"), &config), CompressionLevel::Ultra);
+        assert_eq!(file_level(Path::new("dir/run.sh"), &config), CompressionLevel::Fast);
+        assert_eq!(file_level(Path::new("dir/tool"), &config), config.compression_level);
+        assert!(parse_args(&args(&["--map", "so", "prog"])).is_err());
+        assert!(parse_args(&args(&["--map", "so=max", "--auto", "prog"])).is_err());
+        assert!(parse_args(&args(&["--json", "--info", "prog"])).is_err());
+        assert!(parse_args(&args(&["--entry", "prog", "prog"])).is_err());
+        assert!(parse_args(&args(&["--bundle", "out", "--encrypt", "prog"])).is_err());
+        assert!(parse_args(&args(&["--bundle", "out", "--entry", "prog", "-3", "prog"])).is_ok());
+        assert!(parse_args(&args(&["--bundle", "out", "--json", "prog"])).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_min_ratio_skips_file() -> io::Result<()> {
+        let test_file = env::temp_dir().join("zexe_test_min_ratio");
+        let content = b"#!/bin/sh\necho 'too small to pay off'\n";
+        write_executable(&test_file, content)?;
+        let _ = fs::remove_file(test_file.with_extension("~"));
+
+        let config = Config { min_ratio: Some(10.0), ..test_config(vec![test_file.clone()]) };
+        assert!(compress_file(&test_file, &config)?.is_none());
+        assert_eq!(fs::read(&test_file)?, content);
+        assert!(!test_file.with_extension("~").exists());
+        assert!(!has_temp_files(&test_file)?);
+
+        fs::remove_file(&test_file)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_header_size() -> io::Result<()> {
+        let original = Original {
+            name: "prog".to_string(),
+            mode: 0o755,
+            cksum: posix_cksum(&b"payload"[..])?,
+            xattrs: Vec::new(),
+        };
+        for (encrypt, cache, extract_only) in [(false, false, false), (true, false, false),
+                                               (false, true, false), (false, false, true)] {
+            let config = Config { encrypt, cache, extract_only, ..test_config(Vec::new()) };
+            let header = generate_header(&config, &original, false, None)?;
+            assert_eq!(header.len() % HEADER_SIZE, 0);
+            assert_eq!(parse_header_size(&header), Some(header.len()));
+            let tail = format!("tail -c +{} ", header.len() + 1);
+            assert!(header.windows(tail.len()).any(|w| w == tail.as_bytes()));
+
+            // Every mode stops the shell before the padding and the payload,
+            // even if exec fails
+            let end = header.iter().rposition(|&b| b != b'#' && b != b'\n').unwrap();
+            let script = header[..=end].trim_ascii_end();
+            let last = script.rsplit(|&b| b == b'\n').next().unwrap();
+            assert!(last.starts_with(b"exit "), "{}", String::from_utf8_lossy(last));
+
+            // Some of these names end the header right on a block boundary
+            for len in 1..=2 * HEADER_SIZE {
+                let original = Original { name: "p".repeat(len), mode: 0o755, cksum: original.cksum,
+                                          xattrs: Vec::new() };
+                let header = generate_header(&config, &original, false, None)?;
+                assert_eq!(parse_header_size(&header), Some(header.len()));
+                assert_eq!(header.last(), Some(&b'\n'));
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_detect_binary_format() {
+        assert_eq!(detect_binary_format(b"\x7fELF\x02\x01"), Some(BinaryFormat::Elf));
+        assert_eq!(detect_binary_format(&[0xcf, 0xfa, 0xed, 0xfe, 7]), Some(BinaryFormat::MachO));
+        assert_eq!(detect_binary_format(&[0xfe, 0xed, 0xfa, 0xce]), Some(BinaryFormat::MachO));
+        assert_eq!(detect_binary_format(&[0xca, 0xfe, 0xba, 0xbe]), Some(BinaryFormat::MachOFat));
+        assert_eq!(detect_binary_format(b"MZ\x90\x00"), Some(BinaryFormat::Pe));
+        assert_eq!(detect_binary_format(b"#!/bin/sh\n"), None);
+        assert_eq!(detect_binary_format(b"\x7fEL"), None);
+    }
+
+    #[test]
+    fn test_elf_machine() -> io::Result<()> {
+        let mut header = *b"\x7fELF\x02\x01\x01\0\0\0\0\0\0\0\0\0\x02\0\xb7\0";
+        assert_eq!(elf_machine(&header), Some("aarch64"));
+        header[18] = 62;
+        assert_eq!(elf_machine(&header), Some("x86_64"));
+        header[5] = 2;
+        assert_eq!(elf_machine(&header), None);
+        header[18..20].copy_from_slice(&[0, 21]);
+        assert_eq!(elf_machine(&header), Some("powerpc64"));
+        assert_eq!(elf_machine(&header[..19]), None);
+        assert_eq!(elf_machine(b"#!/bin/sh\nexit 0\n\n\n\n"), None);
+        #[cfg(target_os = "linux")]
+        assert_eq!(read_elf_machine(Path::new("/bin/sh"))?, Some(env::consts::ARCH));
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(target_os = "macos")]
+    fn test_macos_system_binary_is_macho() -> io::Result<()> {
+        let format = read_binary_format(Path::new("/bin/ls"))?;
+        assert!(matches!(format, Some(BinaryFormat::MachO) | Some(BinaryFormat::MachOFat)));
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_linux_system_binary_is_elf() -> io::Result<()> {
+        assert_eq!(read_binary_format(Path::new("/bin/sh"))?, Some(BinaryFormat::Elf));
+        Ok(())
+    }
+
+    #[test]
+    fn test_compress_auto_keeps_smallest() -> io::Result<()> {
+        let data = b"auto mode picks the smallest output ".repeat(50);
+        let config = test_config(Vec::new());
+
+        let (level, compressed) = compress_auto(&data, &config)?;
+        for other in PRESET_LEVELS {
+            let candidate = compress_zopfli(&data, level_options(other, &config), BlockType::Dynamic)?;
+            assert!(compressed.len() <= candidate.len(), "{:?} beat {:?}", other, level);
+        }
+
+        let mut decompressed = Vec::new();
+        GzDecoder::new(&compressed[..]).read_to_end(&mut decompressed)?;
+        assert_eq!(decompressed, data);
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_size() {
+        assert_eq!(format_size(0), "0B");
+        assert_eq!(format_size(1023), "1023B");
+        assert_eq!(format_size(1536), "1.5KB");
+        assert_eq!(format_size(412 * 1024 * 1024), "412.0MB");
+    }
+
+    #[test]
+    fn test_compress_zopfli_parallel() -> io::Result<()> {
+        assert_eq!(parallel_chunk_size(10 << 20, 4), 10 << 18);
+        assert_eq!(parallel_chunk_size(100, 4), 1 << 20);
+        assert_eq!(threads_used(10 << 20, 4), 4);
+        assert_eq!(threads_used(3 << 20, 8), 3);
+        assert_eq!(threads_used(100, 4), 1);
+        assert_eq!(threads_used(10 << 20, 1), 1);
+
+        let data: Vec<u8> = (0..40_000u32).map(|i| (i.wrapping_mul(2654435761) >> 28) as u8).collect();
+        let options = Options { iteration_count: NonZeroU64::new(1).unwrap(), ..Options::default() };
+        let compressed = compress_zopfli_parallel(&data, options, BlockType::Dynamic, 10_000)?;
+
+        // One member per chunk, each starting with the gzip magic
+        let members = compressed.windows(3).filter(|w| w == &[0x1f, 0x8b, 8]).count();
+        assert!(members >= 4, "{} members", members);
+        let mut decompressed = Vec::new();
+        MultiGzDecoder::new(&compressed[..]).read_to_end(&mut decompressed)?;
+        assert_eq!(decompressed, data);
+
+        let mut empty = Vec::new();
+        MultiGzDecoder::new(&compress_zopfli_parallel(&[], options, BlockType::Dynamic, 10_000)?[..])
+            .read_to_end(&mut empty)?;
+        assert!(empty.is_empty());
+        Ok(())
+    }
+
     #[test]
     fn test_zopfli_compression_levels() -> io::Result<()> {
         let test_data = b"Hello world! This is a test string that should compress well. ".repeat(100);
@@ -0,0 +1,163 @@
+// Checks of the built binary: exit statuses, output streams and the parts
+// that run in a separate process (gpg)
+use std::env;
+use std::fs;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::process::{self, Command, Stdio};
+
+const EXE: &str = env!("CARGO_BIN_EXE_zexe");
+
+// The statuses documented in `zexe -h`
+const EXIT_USAGE: i32 = 2;
+const EXIT_REFUSED: i32 = 3;
+const EXIT_DAMAGED: i32 = 5;
+
+fn zexe() -> Command {
+    Command::new(EXE)
+}
+
+fn test_dir(name: &str) -> io::Result<std::path::PathBuf> {
+    let dir = env::temp_dir().join(format!("zexe_cli_{}_{}", name, process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+fn write_executable(path: &Path, content: &[u8]) -> io::Result<()> {
+    fs::write(path, content)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o755))
+}
+
+#[test]
+fn test_usage_exit_status() -> io::Result<()> {
+    assert_eq!(zexe().arg("-h").output()?.status.code(), Some(0));
+    assert_eq!(zexe().arg("--bogus").output()?.status.code(), Some(EXIT_USAGE));
+    Ok(())
+}
+
+#[test]
+fn test_level_env() -> io::Result<()> {
+    // $ZEXE_LEVEL is only consulted without a level on the command line
+    let stderr = |extra: &[&str]| -> io::Result<String> {
+        let output = zexe().env("ZEXE_LEVEL", "bogus").args(extra).arg("/nonexistent/zexe").output()?;
+        Ok(String::from_utf8_lossy(&output.stderr).into_owned())
+    };
+    assert!(stderr(&[])?.contains("ignoring ZEXE_LEVEL=bogus"));
+    assert!(!stderr(&["-1"])?.contains("ZEXE_LEVEL"));
+    Ok(())
+}
+
+#[test]
+fn test_fail_fast() -> io::Result<()> {
+    let output = zexe().args(["--fail-fast", "/nonexistent/a", "/nonexistent/b"]).output()?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("/nonexistent/a") && !stderr.contains("/nonexistent/b"), "{}", stderr);
+    assert!(stderr.contains("1 files left untouched"), "{}", stderr);
+    Ok(())
+}
+
+#[test]
+fn test_damaged_exit_status() -> io::Result<()> {
+    // A damaged payload (here the gzip CRC) is told apart from other errors
+    let dir = test_dir("damaged")?;
+    let test_file = dir.join("prog");
+    write_executable(&test_file, b"#!/bin/sh\necho damaged\n")?;
+    let output = zexe().args(["--any", "--store"]).arg(&test_file).output()?;
+    assert!(String::from_utf8_lossy(&output.stdout).contains("(stored)"));
+    assert!(zexe().args(["--any", "-1", "-f"]).arg(&test_file).status()?.success());
+    let mut bytes = fs::read(&test_file)?;
+    let crc = bytes.len() - 8;
+    bytes[crc] ^= 0xff;
+    fs::write(&test_file, bytes)?;
+    let output = zexe().arg("-d").arg(&test_file).output()?;
+    assert_eq!(output.status.code(), Some(EXIT_DAMAGED), "{}", String::from_utf8_lossy(&output.stderr));
+    let output = zexe().arg("-t").arg(&test_file).output()?;
+    assert_eq!(output.status.code(), Some(EXIT_DAMAGED));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), format!("{}: CORRUPT\n", test_file.display()));
+    let output = zexe().args(["--any", "-1"]).arg(&test_file).output()?;
+    assert_eq!(output.status.code(), Some(EXIT_REFUSED));
+    fs::remove_dir_all(&dir)
+}
+
+#[test]
+fn test_json_output() -> io::Result<()> {
+    // --json keeps stdout to one object per line, progress and errors go to stderr
+    let output = zexe().args(["--json", "--fail-fast", "/nonexistent/a", "/nonexistent/b"]).output()?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(lines.len(), 2, "{}", stdout);
+    assert!(lines[0].starts_with("{\"file\":\"/nonexistent/a\"") && lines[0].contains("\"status\":\"error\""));
+    assert!(lines[1].contains("\"summary\":true") && lines[1].contains("\"untouched\":1"));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("/nonexistent/a"));
+
+    // Including the levels --auto tries
+    let dir = test_dir("json_auto")?;
+    let test_file = dir.join("prog");
+    write_executable(&test_file, b"#!/bin/sh\necho auto\n")?;
+    let output = zexe().args(["--json", "--auto", "--any"]).arg(&test_file).output()?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.lines().all(|line| line.starts_with('{')), "{}", stdout);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("<- selected"));
+    fs::remove_dir_all(&dir)
+}
+
+#[test]
+fn test_selftest() -> io::Result<()> {
+    let output = zexe().arg("--selftest").output()?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(output.status.code(), Some(0), "{}", stdout);
+    assert!(stdout.contains("stored     PASS") && !stdout.contains("FAIL"), "{}", stdout);
+    Ok(())
+}
+
+#[test]
+fn test_gpg_detached_signature() -> io::Result<()> {
+    // gpg runs inside zexe, so the binary is given the key ring
+    let dir = test_dir("gpg")?;
+    fs::create_dir_all(dir.join("gnupg"))?;
+    fs::set_permissions(dir.join("gnupg"), fs::Permissions::from_mode(0o700))?;
+    let gnupg = |program: &Path| {
+        let mut cmd = Command::new(program);
+        cmd.env("GNUPGHOME", dir.join("gnupg"));
+        cmd
+    };
+    let generated = gnupg(Path::new("gpg"))
+        .args(["--batch", "--passphrase", "", "--quick-gen-key", "zexe test <test@example.org>",
+               "ed25519", "sign", "never"])
+        .stderr(Stdio::null())
+        .status();
+    if !generated.is_ok_and(|s| s.success()) {
+        return fs::remove_dir_all(&dir);
+    }
+
+    let exe = Path::new(EXE);
+    let test_file = dir.join("prog");
+    write_executable(&test_file, b"#!/bin/sh\necho 'Hello GPG'\n")?;
+    let status = gnupg(exe).args(["--any", "-1", "--gpg-sign", "test@example.org"]).arg(&test_file).status()?;
+    assert!(status.success());
+    assert!(dir.join("prog.sig").exists());
+    let output = gnupg(exe).arg("--verify-sig").arg(&test_file).output()?;
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("zexe test <test@example.org>"));
+
+    let mut packed = fs::read(&test_file)?;
+    *packed.last_mut().unwrap() ^= 1;
+    fs::write(&test_file, &packed)?;
+    assert!(!gnupg(exe).arg("--verify-sig").arg(&test_file).output()?.status.success());
+
+    let _ = gnupg(Path::new("gpgconf")).args(["--kill", "gpg-agent"]).status();
+    fs::remove_dir_all(&dir)
+}
+
+#[test]
+fn test_missing_file() -> io::Result<()> {
+    for action in [&["--any"][..], &["-d"], &["-t"], &["--info"]] {
+        let output = zexe().args(action).arg("/nonexistent/zexe").output()?;
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert_eq!(output.status.code(), Some(EXIT_REFUSED), "{:?}: {}", action, stderr);
+        assert!(stderr.contains("file does not exist"), "{:?}: {}", action, stderr);
+    }
+    Ok(())
+}